@@ -0,0 +1,135 @@
+//! Throughput of message transfers through a whole session, with DATA and
+//! with BDAT, for messages of a few megabytes.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use rs_smtp::backend::{Address, Backend, MailOptions, RcptOptions, Session};
+use rs_smtp::conn::{Conn, Transport};
+use rs_smtp::server::Server;
+
+struct SinkBackend;
+
+struct SinkSession;
+
+impl Backend for SinkBackend {
+    type S = SinkSession;
+
+    fn new_session<T: Transport>(&self, _c: &mut Conn<Self, T>) -> Result<SinkSession> {
+        Ok(SinkSession)
+    }
+}
+
+#[async_trait]
+impl Session for SinkSession {
+    async fn mail(&mut self, _: &Address, _: &MailOptions) -> Result<()> {
+        Ok(())
+    }
+
+    async fn rcpt(&mut self, _: &Address, _: &RcptOptions) -> Result<()> {
+        Ok(())
+    }
+
+    async fn data<R: AsyncRead + Send + Unpin>(&mut self, mut r: R) -> Result<()> {
+        io::copy(&mut r, &mut io::sink()).await?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {}
+
+    fn logout(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A message of `size` bytes in lines of 78 characters, every tenth of them
+/// starting with a dot.
+fn message(size: usize) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(size + 80);
+    let mut line = 0;
+    while msg.len() < size {
+        if line % 10 == 0 {
+            msg.push(b'.');
+        }
+        msg.extend_from_slice(&[b'x'; 77]);
+        msg.extend_from_slice(b"\r\n");
+        line += 1;
+    }
+    msg
+}
+
+fn data_script(msg: &[u8]) -> Vec<u8> {
+    let mut script = b"EHLO bench\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n".to_vec();
+    for line in msg.split_inclusive(|&c| c == b'\n') {
+        if line.starts_with(b".") {
+            script.push(b'.');
+        }
+        script.extend_from_slice(line);
+    }
+    script.extend_from_slice(b".\r\nQUIT\r\n");
+    script
+}
+
+fn bdat_script(msg: &[u8]) -> Vec<u8> {
+    let mut script = b"EHLO bench\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\n".to_vec();
+    let mut chunks = msg.chunks(1 << 20).peekable();
+    while let Some(chunk) = chunks.next() {
+        let last = if chunks.peek().is_none() { " LAST" } else { "" };
+        script.extend_from_slice(format!("BDAT {}{}\r\n", chunk.len(), last).as_bytes());
+        script.extend_from_slice(chunk);
+    }
+    script.extend_from_slice(b"QUIT\r\n");
+    script
+}
+
+async fn run_session(server: Arc<Server<SinkBackend>>, script: &[u8]) {
+    let (mut client, stream) = io::duplex(64 * 1024);
+    let handle = tokio::spawn(async move {
+        let _ = server.handle_conn(Conn::new(stream, server.max_line_length)).await;
+    });
+
+    let (mut rx, mut tx) = io::split(&mut client);
+    let write = async {
+        tx.write_all(script).await.unwrap();
+    };
+    let read = async {
+        let mut replies = Vec::new();
+        rx.read_to_end(&mut replies).await.unwrap();
+        assert!(replies.windows(4).any(|w| w == b"221 "));
+    };
+    tokio::join!(write, read);
+    handle.await.unwrap();
+}
+
+fn bench_transfers(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut server = Server::new(SinkBackend);
+    server.domain = "localhost".to_string();
+    server.max_message_bytes = 0;
+    let server = Arc::new(server);
+
+    let mut group = c.benchmark_group("transfer");
+    group.sample_size(20);
+    for size in [1 << 20, 4 << 20, 16 << 20] {
+        let msg = message(size);
+        group.throughput(Throughput::Bytes(msg.len() as u64));
+
+        let script = data_script(&msg);
+        group.bench_with_input(BenchmarkId::new("DATA", size >> 20), &script, |b, script| {
+            b.to_async(&rt).iter(|| run_session(server.clone(), script));
+        });
+
+        let script = bdat_script(&msg);
+        group.bench_with_input(BenchmarkId::new("BDAT", size >> 20), &script, |b, script| {
+            b.to_async(&rt).iter(|| run_session(server.clone(), script));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_transfers);
+criterion_main!(benches);
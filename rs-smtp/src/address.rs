@@ -0,0 +1,450 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, bail, Result};
+
+/// A mailbox from a `MAIL FROM` reverse-path or a `RCPT TO` forward-path,
+/// with the case the client sent. With SMTPUTF8 (RFC 6531) the local part
+/// and domain may contain UTF-8.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    /// The local part as sent, quoted local parts keeping their quotes.
+    pub local_part: String,
+    /// The domain, or an address literal such as `[IPv6:::1]` including its
+    /// brackets. Empty for the null reverse-path and for `<Postmaster>`.
+    pub domain: String,
+}
+
+impl Address {
+    /// The null reverse-path `<>`, used for bounces.
+    pub fn null() -> Self {
+        Address {
+            local_part: String::new(),
+            domain: String::new(),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.local_part.is_empty() && self.domain.is_empty()
+    }
+
+    /// The local part with the quoting of a quoted local part removed.
+    pub fn unquoted_local_part(&self) -> String {
+        match self.local_part.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            Some(quoted) => {
+                let mut out = String::with_capacity(quoted.len());
+                let mut chars = quoted.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => out.extend(chars.next()),
+                        c => out.push(c),
+                    }
+                }
+                out
+            }
+            None => self.local_part.clone(),
+        }
+    }
+
+    /// Whether the address is all ASCII, i.e. usable without SMTPUTF8.
+    pub fn is_ascii(&self) -> bool {
+        self.local_part.is_ascii() && self.domain.is_ascii()
+    }
+
+    /// The domain with internationalized labels as A-labels (`xn--...`),
+    /// lowercased. Address literals are returned as they are.
+    pub fn ascii_domain(&self) -> Result<String> {
+        if self.domain.starts_with('[') {
+            return Ok(self.domain.clone());
+        }
+        domain_to_ascii(&self.domain)
+    }
+
+    /// The domain with internationalized labels as U-labels, lowercased.
+    /// Address literals are returned as they are.
+    pub fn unicode_domain(&self) -> String {
+        if self.domain.starts_with('[') {
+            return self.domain.clone();
+        }
+        idna::domain_to_unicode(&self.domain).0
+    }
+
+    /// The IP address of an `[1.2.3.4]` or `[IPv6:...]` address literal
+    /// domain.
+    pub fn ip_literal(&self) -> Option<IpAddr> {
+        let literal = self.domain.strip_prefix('[')?.strip_suffix(']')?;
+        match literal.get(..5) {
+            Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => literal[5..].parse::<Ipv6Addr>().ok().map(IpAddr::V6),
+            _ => literal.parse::<Ipv4Addr>().ok().map(IpAddr::V4),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.domain.is_empty() {
+            write!(f, "{}", self.local_part)
+        } else {
+            write!(f, "{}@{}", self.local_part, self.domain)
+        }
+    }
+}
+
+impl std::str::FromStr for Address {
+    type Err = anyhow::Error;
+
+    /// Parses a bare RFC 5321 Mailbox, `local-part@domain`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut p = Parser::new(s);
+        let addr = p.mailbox()?;
+        if !p.rest().is_empty() {
+            bail!("smtp: unexpected {:?} after address", p.rest());
+        }
+        Ok(addr)
+    }
+}
+
+/// Parses the reverse-path at the start of a `MAIL FROM:` argument, either a
+/// path or the null sender `<>`. Returns the address and the rest of the
+/// argument, the ESMTP parameters. Unless `strict`, a mailbox without angle
+/// brackets is accepted too.
+pub(crate) fn parse_reverse_path(s: &str, strict: bool) -> Result<(Address, &str)> {
+    let mut p = Parser::new(s);
+    let addr = if p.eat("<>") {
+        Address::null()
+    } else {
+        p.path(strict, false)?
+    };
+    Ok((addr, p.params()?))
+}
+
+/// Parses the forward-path at the start of a `RCPT TO:` argument, which may
+/// also be `<Postmaster>` without a domain. Returns the address and the rest
+/// of the argument, the ESMTP parameters.
+pub(crate) fn parse_forward_path(s: &str, strict: bool) -> Result<(Address, &str)> {
+    let mut p = Parser::new(s);
+    let addr = p.path(strict, true)?;
+    Ok((addr, p.params()?))
+}
+
+/// Converts a domain to its A-label form, failing if it isn't a valid IDNA
+/// domain name.
+pub(crate) fn domain_to_ascii(domain: &str) -> Result<String> {
+    idna::domain_to_ascii(domain).map_err(|_| anyhow!("smtp: invalid internationalized domain {:?}", domain))
+}
+
+// Characters allowed in an atom besides letters and digits (RFC 5322 atext).
+const ATEXT_SPECIALS: &str = "!#$%&'*+-/=?^_`{|}~";
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(s: &'a str) -> Self {
+        Parser { s, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.rest().starts_with(prefix) {
+            self.pos += prefix.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        match self.peek() {
+            Some(found) if found == c => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            Some(found) => bail!("smtp: expected {:?} in address, found {:?}", c, found),
+            None => bail!("smtp: expected {:?} in address, found end of input", c),
+        }
+    }
+
+    /// Consumes characters while `f` accepts them, returning them.
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let start = self.pos;
+        let len = self.rest().find(|c| !f(c)).unwrap_or(self.rest().len());
+        self.pos += len;
+        &self.s[start..start + len]
+    }
+
+    /// The ESMTP parameters after a path, which must be separated from it by
+    /// a space.
+    fn params(&self) -> Result<&'a str> {
+        let rest = self.rest();
+        if !rest.is_empty() && !rest.starts_with(' ') {
+            bail!("smtp: unexpected {:?} after address", rest);
+        }
+        Ok(rest.trim_start_matches(' '))
+    }
+
+    // Path = "<" [ A-d-l ":" ] Mailbox ">"
+    fn path(&mut self, strict: bool, allow_postmaster: bool) -> Result<Address> {
+        if !self.eat("<") {
+            if strict {
+                bail!("smtp: missing opening angle bracket");
+            }
+            let rest = self.rest();
+            let end = rest.find(' ').unwrap_or(rest.len());
+            let addr = rest[..end].parse()?;
+            self.pos += end;
+            return Ok(addr);
+        }
+
+        // Source routes are obsolete and must be ignored (RFC 5321 section
+        // 4.1.1.3 and appendix C).
+        if self.peek() == Some('@') {
+            loop {
+                self.expect('@')?;
+                self.domain()?;
+                if !self.eat(",") {
+                    break;
+                }
+            }
+            self.expect(':')?;
+        }
+
+        let local_part = self.local_part()?;
+        if allow_postmaster && self.peek() == Some('>') && local_part.eq_ignore_ascii_case("postmaster") {
+            self.pos += 1;
+            return Ok(Address {
+                local_part,
+                domain: String::new(),
+            });
+        }
+        self.expect('@')?;
+        let domain = self.domain_or_literal()?;
+        self.expect('>')?;
+        Ok(Address { local_part, domain })
+    }
+
+    // Mailbox = Local-part "@" ( Domain / address-literal )
+    fn mailbox(&mut self) -> Result<Address> {
+        let local_part = self.local_part()?;
+        self.expect('@')?;
+        let domain = self.domain_or_literal()?;
+        Ok(Address { local_part, domain })
+    }
+
+    // Local-part = Dot-string / Quoted-string
+    fn local_part(&mut self) -> Result<String> {
+        let start = self.pos;
+        if self.eat("\"") {
+            loop {
+                match self.peek() {
+                    Some('"') => {
+                        self.pos += 1;
+                        break;
+                    }
+                    Some('\\') => {
+                        self.pos += 1;
+                        match self.peek() {
+                            Some(c) if (' '..='~').contains(&c) => self.pos += 1,
+                            _ => bail!("smtp: invalid quoted-pair in local part"),
+                        }
+                    }
+                    Some(c) if (' '..='~').contains(&c) || !c.is_ascii() => self.pos += c.len_utf8(),
+                    Some(c) => bail!("smtp: invalid character {:?} in quoted local part", c),
+                    None => bail!("smtp: unterminated quoted local part"),
+                }
+            }
+        } else {
+            loop {
+                let atom = self.take_while(|c| c.is_ascii_alphanumeric() || ATEXT_SPECIALS.contains(c) || !c.is_ascii());
+                if atom.is_empty() {
+                    bail!("smtp: invalid local part");
+                }
+                if !self.eat(".") {
+                    break;
+                }
+            }
+        }
+        Ok(self.s[start..self.pos].to_string())
+    }
+
+    fn domain_or_literal(&mut self) -> Result<String> {
+        if self.peek() == Some('[') {
+            self.address_literal()
+        } else {
+            self.domain()
+        }
+    }
+
+    // Domain = sub-domain *("." sub-domain)
+    // sub-domain = Let-dig [Ldh-str] / U-label
+    fn domain(&mut self) -> Result<String> {
+        let start = self.pos;
+        loop {
+            let label = self.take_while(|c| c.is_ascii_alphanumeric() || c == '-' || !c.is_ascii());
+            if label.is_empty() || label.starts_with('-') || label.ends_with('-') {
+                bail!("smtp: invalid domain");
+            }
+            if !self.eat(".") {
+                break;
+            }
+        }
+
+        let domain = &self.s[start..self.pos];
+        if !domain.is_ascii() {
+            domain_to_ascii(domain)?;
+        }
+        Ok(domain.to_string())
+    }
+
+    // address-literal = "[" ( IPv4-address-literal / IPv6-address-literal /
+    //                         General-address-literal ) "]"
+    fn address_literal(&mut self) -> Result<String> {
+        let start = self.pos;
+        self.expect('[')?;
+        let literal = self.take_while(|c| c != ']' && c != '>' && c != ' ');
+        self.expect(']')?;
+
+        let valid = match literal.split_once(':') {
+            Some((tag, addr)) if tag.eq_ignore_ascii_case("IPv6") => addr.parse::<Ipv6Addr>().is_ok(),
+            // General-address-literal = Standardized-tag ":" 1*dcontent
+            Some((tag, content)) => {
+                !tag.is_empty()
+                    && !tag.ends_with('-')
+                    && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                    && !content.is_empty()
+                    && content.chars().all(|c| ('!'..='Z').contains(&c) || ('^'..='~').contains(&c))
+            }
+            None => literal.parse::<Ipv4Addr>().is_ok(),
+        };
+        if !valid {
+            bail!("smtp: invalid address literal {:?}", literal);
+        }
+        Ok(self.s[start..self.pos].to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(local_part: &str, domain: &str) -> Address {
+        Address {
+            local_part: local_part.to_string(),
+            domain: domain.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_reverse_paths() {
+        assert_eq!(parse_reverse_path("<>", true).unwrap(), (Address::null(), ""));
+        assert_eq!(
+            parse_reverse_path("<John.Doe@Example.COM> SIZE=10", true).unwrap(),
+            (addr("John.Doe", "Example.COM"), "SIZE=10")
+        );
+        assert_eq!(
+            parse_reverse_path("a@b.c  BODY=8BITMIME", false).unwrap(),
+            (addr("a", "b.c"), "BODY=8BITMIME")
+        );
+    }
+
+    #[test]
+    fn parses_forward_paths() {
+        assert_eq!(parse_forward_path("<Postmaster>", true).unwrap(), (addr("Postmaster", ""), ""));
+        assert_eq!(
+            parse_forward_path("<@a.example,@b.example:user@c.example>", true).unwrap(),
+            (addr("user", "c.example"), "")
+        );
+        assert_eq!(
+            parse_forward_path(r#"<"John \"Doe\""@example.com> NOTIFY=NEVER"#, true).unwrap(),
+            (addr(r#""John \"Doe\"""#, "example.com"), "NOTIFY=NEVER")
+        );
+        assert_eq!(parse_forward_path("<x@[192.0.2.1]>", true).unwrap().0, addr("x", "[192.0.2.1]"));
+        assert_eq!(parse_forward_path("<x@[IPv6:2001:db8::1]>", true).unwrap().0, addr("x", "[IPv6:2001:db8::1]"));
+        assert_eq!(parse_forward_path("<x@[tag:content]>", true).unwrap().0, addr("x", "[tag:content]"));
+        assert_eq!(parse_forward_path("<jörg@bücher.example>", true).unwrap().0, addr("jörg", "bücher.example"));
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        for path in [
+            "",
+            "<",
+            "<>",
+            "a@b",
+            "<a@b",
+            "<a@b>x",
+            "<@a.example:>",
+            "<@a.example,user@c.example>",
+            "<user>",
+            "<a b@c>",
+            "<.a@b>",
+            "<a..b@c>",
+            "<a.@b>",
+            "<\"a@b>",
+            "<\"a\\é\"@b>",
+            "<a@>",
+            "<a@-b.c>",
+            "<a@b-.c>",
+            "<a@b..c>",
+            "<a@[999.1.1.1]>",
+            "<a@[IPv6:zz]>",
+            "<a@[1.2.3.4>",
+            "<a@[-:x]>",
+            "<a@[tag:]>",
+            "<é>",
+            "<a@é.>",
+        ] {
+            assert!(parse_forward_path(path, true).is_err(), "{:?}", path);
+        }
+        assert!(parse_reverse_path("<Postmaster>", true).is_err());
+        assert!(parse_reverse_path("a@b", true).is_err());
+    }
+
+    #[test]
+    fn parses_mailboxes() {
+        assert_eq!("a@b.c".parse::<Address>().unwrap(), addr("a", "b.c"));
+        assert!("a@b.c d".parse::<Address>().is_err());
+        assert!("a".parse::<Address>().is_err());
+        assert!("ü".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn unquotes_local_parts() {
+        assert_eq!(addr(r#""John \"Doe\"""#, "x").unquoted_local_part(), r#"John "Doe""#);
+        assert_eq!(addr("plain", "x").unquoted_local_part(), "plain");
+    }
+
+    #[test]
+    fn converts_domains() {
+        let a = addr("a", "Bücher.example");
+        assert!(!a.is_ascii());
+        assert_eq!(a.ascii_domain().unwrap(), "xn--bcher-kva.example");
+        assert_eq!(addr("a", "xn--bcher-kva.example").unicode_domain(), "bücher.example");
+        assert_eq!(addr("a", "[192.0.2.1]").ascii_domain().unwrap(), "[192.0.2.1]");
+    }
+
+    #[test]
+    fn parses_ip_literals() {
+        assert_eq!(addr("a", "[192.0.2.1]").ip_literal(), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(addr("a", "[ipv6:::1]").ip_literal(), Some("::1".parse().unwrap()));
+        assert_eq!(addr("a", "[tag:x]").ip_literal(), None);
+        assert_eq!(addr("a", "[é]").ip_literal(), None);
+        assert_eq!(addr("a", "example.com").ip_literal(), None);
+    }
+
+    #[test]
+    fn displays_addresses() {
+        assert_eq!(addr("a", "b.c").to_string(), "a@b.c");
+        assert_eq!(addr("Postmaster", "").to_string(), "Postmaster");
+        assert_eq!(Address::null().to_string(), "");
+    }
+}
@@ -1,4 +1,6 @@
-use crate::{conn::Conn, sasl};
+use crate::{conn::{Conn, Transport}, sasl};
+pub use crate::address::Address;
+pub use crate::data::{EnhancedCode, SMTPError, ENHANCED_CODE_NOT_SET, NO_ENHANCED_CODE};
 
 use async_trait::async_trait;
 
@@ -6,51 +8,227 @@ use anyhow::Result;
 
 use tokio::io::AsyncRead;
 
-type BodyType = String;
-
-//const BODY_7BIT: BodyType = "7BIT".to_owned();
-//const BODY_8BIT_MIME: BodyType = "8BITMIME".to_string();
-//const BODY_BINARY_MIME: BodyType = "BINARYMIME".to_string();
+/// The body type of a message, from the `BODY` parameter of `MAIL FROM`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BodyType {
+    #[default]
+    SevenBit,
+    /// 8-bit MIME (RFC 6152).
+    EightBitMime,
+    /// Binary MIME (RFC 3030), only transferred with BDAT.
+    BinaryMime,
+}
 
 pub trait Backend: Send + Sync + 'static + Sized {
     type S: Session + Send;
 
-    fn new_session(&self, c: &mut Conn<Self>) -> Result<Self::S>;
+    fn new_session<T: Transport>(&self, c: &mut Conn<Self, T>) -> Result<Self::S>;
+}
+
+/// What a DSN (RFC 3461) should contain of the original message, from the
+/// `RET` parameter of `MAIL FROM`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DSNReturn {
+    Full,
+    Headers,
+}
+
+/// When a DSN should be sent for a recipient, from the `NOTIFY` parameter of
+/// `RCPT TO`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DSNNotify {
+    Never,
+    Success,
+    Failure,
+    Delay,
+}
+
+/// The original recipient of a forwarded message, from the `ORCPT`
+/// parameter of `RCPT TO`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OriginalRecipient {
+    /// The address type, usually `rfc822`.
+    pub addr_type: String,
+    /// The xtext decoded address.
+    pub addr: String,
 }
 
 pub struct MailOptions {
     pub body: BodyType,
     pub size: usize,
+    /// The REQUIRETLS parameter (RFC 8689): the message must only be relayed
+    /// over TLS with a validated certificate.
     pub require_tls: bool,
     pub utf8: bool,
     pub auth: String,
+    /// The DSN `RET` parameter.
+    pub ret: Option<DSNReturn>,
+    /// The xtext decoded DSN `ENVID` parameter.
+    pub envelope_id: Option<String>,
+}
+
+impl Default for MailOptions {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MailOptions {
     pub fn new() -> Self {
         MailOptions {
-            body: "7BIT".to_string(),
+            body: BodyType::SevenBit,
             size: 0,
             require_tls: false,
             utf8: false,
             auth: String::new(),
+            ret: None,
+            envelope_id: None,
+        }
+    }
+
+    /// Whether the sender asked for TLS to be optional when relaying the
+    /// message, with a `TLS-Required: No` header field (RFC 8689). Only the
+    /// header section of `message` is looked at. The header field is ignored
+    /// if the transaction used REQUIRETLS.
+    pub fn tls_optional(&self, message: &[u8]) -> bool {
+        !self.require_tls && has_tls_required_no(message)
+    }
+}
+
+/// Looks for a `TLS-Required: No` field in the header section of `message`.
+fn has_tls_required_no(message: &[u8]) -> bool {
+    let mut lines = message.split(|&c| c == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line)).peekable();
+    while let Some(line) = lines.next() {
+        // The header section ends at the first empty line.
+        if line.is_empty() {
+            break;
+        }
+        let Some(colon) = line.iter().position(|&c| c == b':') else {
+            continue;
+        };
+        if !line[..colon].trim_ascii_end().eq_ignore_ascii_case(b"TLS-Required") {
+            continue;
+        }
+
+        // The value may be folded over several lines.
+        let mut value = line[colon + 1..].to_vec();
+        while let Some(next) = lines.next_if(|l| l.starts_with(b" ") || l.starts_with(b"\t")) {
+            value.extend_from_slice(next);
+        }
+        return value.trim_ascii().eq_ignore_ascii_case(b"No");
+    }
+    false
+}
+
+/// The ESMTP parameters of a `RCPT TO` command.
+#[derive(Default)]
+pub struct RcptOptions {
+    /// The DSN `NOTIFY` parameter, empty if not given.
+    pub notify: Vec<DSNNotify>,
+    /// The DSN `ORCPT` parameter.
+    pub original_recipient: Option<OriginalRecipient>,
+}
+
+impl RcptOptions {
+    pub fn new() -> Self {
+        RcptOptions {
+            notify: Vec::new(),
+            original_recipient: None,
         }
     }
 }
 
+/// Collects the per-recipient status of an LMTP transaction. Each accepted
+/// recipient gets its own reply after DATA or the last BDAT chunk; recipients
+/// without an explicit status get the result of `Session::lmtp_data`.
+pub struct StatusCollector {
+    statuses: Vec<(String, Option<Result<()>>)>,
+}
+
+impl StatusCollector {
+    pub fn new(recipients: Vec<String>) -> Self {
+        StatusCollector {
+            statuses: recipients.into_iter().map(|rcpt| (rcpt, None)).collect(),
+        }
+    }
+
+    /// The accepted recipients, in the order of their RCPT commands.
+    pub fn recipients(&self) -> Vec<&str> {
+        self.statuses.iter().map(|(rcpt, _)| rcpt.as_str()).collect()
+    }
+
+    /// Sets the delivery status for a recipient. An error is reported as a
+    /// failed delivery for that recipient only. If the same address was
+    /// accepted more than once, each call sets the next pending status.
+    pub fn set_status(&mut self, rcpt: &str, status: Result<()>) {
+        if let Some((_, s)) = self.statuses.iter_mut().find(|(r, s)| r == rcpt && s.is_none()) {
+            *s = Some(status);
+        }
+    }
+
+    pub fn into_statuses(self) -> Vec<(String, Option<Result<()>>)> {
+        self.statuses
+    }
+}
+
 #[async_trait]
 pub trait Session {
     fn authenticators(&mut self) -> Vec<Box<dyn sasl::Server>> {
         Vec::new()
     }
 
-    async fn mail(&mut self, from: &str, opts: &MailOptions) -> Result<()>;
+    /// `from` is the null address for the null reverse-path `<>`.
+    async fn mail(&mut self, from: &Address, opts: &MailOptions) -> Result<()>;
 
-    async fn rcpt(&mut self, to: &str) -> Result<()>;
+    async fn rcpt(&mut self, to: &Address, opts: &RcptOptions) -> Result<()>;
 
     async fn data<R: AsyncRead + Send + Unpin>(&mut self, r: R) -> Result<()>;
 
+    /// Called instead of `data` when the server runs in LMTP mode. The
+    /// session may set a status for each recipient on `status`; by default
+    /// the result of `data` applies to all recipients.
+    async fn lmtp_data<R: AsyncRead + Send + Unpin>(&mut self, r: R, _status: &mut StatusCollector) -> Result<()> {
+        self.data(r).await
+    }
+
     fn reset(&mut self);
 
     fn logout(&mut self) -> Result<()>;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_tls_required_no() {
+        assert!(has_tls_required_no(b"Subject: hi\r\nTLS-Required: No\r\n\r\nbody\r\n"));
+        assert!(has_tls_required_no(b"tls-required : no\r\n\r\n"));
+        assert!(has_tls_required_no(b"TLS-Required:\r\n\tNo\r\nSubject: hi\r\n\r\n"));
+        assert!(has_tls_required_no(b"TLS-Required: No\n\nbody\n"));
+    }
+
+    #[test]
+    fn ignores_other_tls_required_values() {
+        assert!(!has_tls_required_no(b"TLS-Required: Yes\r\n\r\n"));
+        assert!(!has_tls_required_no(b"TLS-Required: No thanks\r\n\r\n"));
+        assert!(!has_tls_required_no(b"X-TLS-Required: No\r\n\r\n"));
+        // A folded value is part of the field.
+        assert!(!has_tls_required_no(b"TLS-Required: No\r\n Way\r\n\r\n"));
+    }
+
+    #[test]
+    fn ignores_tls_required_in_body() {
+        assert!(!has_tls_required_no(b"Subject: hi\r\n\r\nTLS-Required: No\r\n"));
+        assert!(!has_tls_required_no(b"\r\nTLS-Required: No\r\n"));
+    }
+
+    #[test]
+    fn require_tls_overrides_header() {
+        let message = b"TLS-Required: No\r\n\r\nbody\r\n";
+        let mut opts = MailOptions::new();
+        assert!(opts.tls_optional(message));
+        opts.require_tls = true;
+        assert!(!opts.tls_optional(message));
+    }
+}
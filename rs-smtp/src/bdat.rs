@@ -0,0 +1,103 @@
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf};
+
+use crate::timeout::{with_timeout, TimedReader};
+
+// How much of a message may sit between the connection and the session
+// before reading chunks from the client waits for the session.
+const PIPE_CAPACITY: usize = 64 * 1024;
+const COPY_BUF_SIZE: usize = 8 * 1024;
+
+/// Creates the pipe carrying the chunks of a BDAT transfer to the message
+/// reader handed to `Session::data`.
+pub fn pipe() -> (ChunkWriter, ChunkReader) {
+    let (tx, rx) = tokio::io::duplex(PIPE_CAPACITY);
+    let complete = Arc::new(AtomicBool::new(false));
+    (
+        ChunkWriter {
+            tx,
+            complete: complete.clone(),
+        },
+        ChunkReader { rx, complete },
+    )
+}
+
+/// The connection's end of a BDAT transfer. Dropping it before `finish`
+/// makes the message reader fail.
+pub struct ChunkWriter {
+    tx: DuplexStream,
+    complete: Arc<AtomicBool>,
+}
+
+impl ChunkWriter {
+    /// Ends the message after its LAST chunk.
+    pub async fn finish(mut self) {
+        self.complete.store(true, Ordering::Release);
+        let _ = self.tx.shutdown().await;
+    }
+}
+
+/// The message reader of a BDAT transfer. It fails with `UnexpectedEof` if
+/// the transfer ends without a LAST chunk, e.g. because the connection was
+/// lost or the transaction was reset, so that a truncated message is never
+/// taken for a whole one.
+pub struct ChunkReader {
+    rx: DuplexStream,
+    complete: Arc<AtomicBool>,
+}
+
+impl AsyncRead for ChunkReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        futures::ready!(Pin::new(&mut this.rx).poll_read(cx, buf))?;
+        if buf.filled().len() == filled && buf.remaining() > 0 && !this.complete.load(Ordering::Acquire) {
+            return Poll::Ready(Err(io::Error::new(ErrorKind::UnexpectedEof, "smtp: message transfer aborted")));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reads a chunk of `size` bytes from `r` and writes it to `w`, a bounded
+/// buffer at a time. Without `w`, or once the session stopped reading, the
+/// rest of the chunk is discarded so that the connection stays in sync. A
+/// session which took none of the message for `stall_timeout` is taken to
+/// have stopped reading.
+///
+/// Returns whether the whole chunk was written. Fails if `r` ends before
+/// the end of the chunk, or with `TimedOut` if the client sent nothing for
+/// `block_timeout`.
+pub async fn copy_chunk<R: AsyncRead + Unpin>(
+    r: &mut R,
+    size: usize,
+    block_timeout: Duration,
+    stall_timeout: Duration,
+    mut w: Option<&mut ChunkWriter>,
+) -> io::Result<bool> {
+    let mut chunk = TimedReader::new(r.take(size as u64), block_timeout);
+    let mut buf = vec![0; COPY_BUF_SIZE.min(size)];
+    let mut remaining = size;
+    let mut written = w.is_some();
+
+    while remaining > 0 {
+        let n = chunk.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "smtp: connection closed in BDAT chunk"));
+        }
+        remaining -= n;
+
+        if let Some(pipe) = w.as_mut() {
+            if !matches!(with_timeout(stall_timeout, pipe.tx.write_all(&buf[..n])).await, Ok(Ok(()))) {
+                w = None;
+                written = false;
+            }
+        }
+    }
+    Ok(written)
+}
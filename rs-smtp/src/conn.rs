@@ -1,41 +1,72 @@
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use log::Level;
 use base64::{
     engine::general_purpose,
     Engine as _,
 };
 use tokio::task::JoinHandle;
-use tokio::time::timeout;
 
-use crate::backend::{Backend, MailOptions, Session};
-use crate::data::{DataReader, EnhancedCode, NO_ENHANCED_CODE};
-//use crate::lengthlimit_reader::LineLimitReader;
-use crate::parse::parse_args;
+use crate::address::{domain_to_ascii, parse_forward_path, parse_reverse_path};
+use crate::bdat::{self, copy_chunk, ChunkWriter};
+use crate::backend::{
+    Backend, BodyType, DSNNotify, DSNReturn, MailOptions, OriginalRecipient, RcptOptions, Session, StatusCollector,
+};
+use crate::data::{DataReader, EnhancedCode, SMTPError, NO_ENHANCED_CODE};
+use crate::limit::client_key;
+use crate::logging::{conn_log, LogContext};
+use crate::lengthlimit_reader::{skip_line, LineLimitReader, TooLongLine};
+use crate::parse::{parse_args, InvalidUtf8};
+use crate::registry::{ConnTracker, TransactionState};
 use crate::sasl;
 use crate::server::Server;
 use crate::stream::MyStream;
+use crate::timeout::{after_idle, with_timeout, TimedReader};
+pub use crate::stream::Transport;
 
-use regex::Regex;
-
-use tokio::io::{self, BufReader, AsyncWriteExt, AsyncReadExt, AsyncBufReadExt};
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
 use tokio::net::TcpStream;
+use tokio::time::error::Elapsed;
+#[cfg(unix)]
+use tokio::net::{unix::UCred, UnixStream};
+use tokio_rustls::server::TlsStream;
+
+// How much of a message left unread by the session is discarded before the
+// connection is closed instead.
+const MAX_DRAIN_BYTES: u64 = 64 * 1024 * 1024;
+
+// How long the session of an aborted BDAT transfer has to give up on the
+// message before it is dropped.
+const ABORTED_TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
 
-//const ERR_THRESHOLD: usize = 3;
+// The maximum lengths of the DSN ENVID and ORCPT values (RFC 3461).
+const MAX_ENVID_LEN: usize = 100;
+const MAX_ORCPT_LEN: usize = 500;
 
-pub struct Conn<B: Backend> {
-    pub stream: BufReader<MyStream>,
+/// The outcome of a BDAT transfer, handed back by the task running
+/// `Session::data`.
+type DataResult<S> = (Result<()>, S, StatusCollector);
+
+pub struct Conn<B: Backend, T: Transport = TcpStream> {
+    pub stream: BufReader<MyStream<T>>,
 
     //pub text: textproto::Conn<MyStream>,
     pub helo: String,
     pub err_count: usize,
+    rejected_rcpts: usize,
 
     pub session: Option<B::S>,
     binarymime: bool,
-    //line_limit_reader: LineLimitReader<StreamState>,
+    /// Whether the current transaction was started with SMTPUTF8.
+    utf8: bool,
+    max_line_length: usize,
 
-    bdat_pipe: Option<io::DuplexStream>,
-    data_result: Option<JoinHandle<(Result<()>, B::S)>>,
+    bdat_pipe: Option<ChunkWriter>,
+    data_result: Option<JoinHandle<DataResult<B::S>>>,
     bytes_received: usize,
 
     from_received: bool,
@@ -43,19 +74,55 @@ pub struct Conn<B: Backend> {
     did_auth: bool,
 
     auths: HashMap<String, Box<dyn sasl::Server>>,
+
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    peer_cred: Option<UCred>,
+    messages: usize,
+
+    tracker: Arc<ConnTracker>,
+}
+
+#[cfg(unix)]
+impl<B: Backend> Conn<B, UnixStream> {
+    /// Creates a connection on a Unix domain socket. The peer credentials are
+    /// looked up once and exposed through `peer_cred`.
+    pub fn new_unix(stream: UnixStream, max_line_length: usize) -> Self {
+        let peer_cred = stream.peer_cred().ok();
+        let mut c = Self::new(stream, max_line_length);
+        c.peer_cred = peer_cred;
+        c
+    }
 }
 
-impl<B: Backend> Conn<B> {
-    pub fn new(stream: TcpStream, _max_line_length: usize) -> Self {
-        return Conn {
-            stream: BufReader::new(MyStream::new(stream)),
+impl<B: Backend, T: Transport> Conn<B, T> {
+    pub fn new(stream: T, max_line_length: usize) -> Self {
+        Self::from_stream(MyStream::new(stream), max_line_length)
+    }
+
+    /// Creates a connection on a stream which already completed its TLS
+    /// handshake, as used by implicit TLS (SMTPS) listeners.
+    pub fn new_tls(stream: TlsStream<T>, max_line_length: usize) -> Self {
+        Self::from_stream(MyStream::new_tls(stream), max_line_length)
+    }
+
+    fn from_stream(mut stream: MyStream<T>, max_line_length: usize) -> Self {
+        let tracker = Arc::new(ConnTracker::new(stream.counters.clone()));
+        tracker.set_tls(stream.is_tls());
+        stream.log.conn_id = tracker.id();
+
+        Conn {
+            stream: BufReader::new(stream),
             //text: textproto::Conn::new(stream.clone()),
             helo: String::new(),
             err_count: 0,
+            rejected_rcpts: 0,
 
             session: None,
             binarymime: false,
-            //line_limit_reader: LineLimitReader::new(stream.clone(), max_line_length),
+            utf8: false,
+            max_line_length,
 
             bdat_pipe: None,
             data_result: None,
@@ -66,18 +133,74 @@ impl<B: Backend> Conn<B> {
             did_auth: false,
 
             auths: HashMap::new(),
-        };
+
+            peer_addr: None,
+            local_addr: None,
+            #[cfg(unix)]
+            peer_cred: None,
+            messages: 0,
+
+            tracker,
+        }
+    }
+
+    /// The ID of this connection in the server's `Registry`.
+    pub fn id(&self) -> u64 {
+        self.tracker.id()
+    }
+
+    pub(crate) fn tracker(&self) -> Arc<ConnTracker> {
+        self.tracker.clone()
+    }
+
+    /// Sets the addresses of the connection. The server does this for the
+    /// connections it accepts, with the client's real addresses when behind
+    /// a PROXY protocol load balancer; call it when passing connections
+    /// accepted elsewhere to `Server::handle_conn`.
+    pub fn set_addrs(&mut self, peer_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) {
+        self.peer_addr = peer_addr;
+        self.local_addr = local_addr;
+        self.tracker.set_addrs(peer_addr, local_addr);
+        self.stream.get_mut().log.peer = peer_addr;
+    }
+
+    pub(crate) fn log(&self) -> &LogContext {
+        &self.stream.get_ref().log
+    }
+
+    /// The key the client is rate limited by, if it has an IP address.
+    fn client_key(&self, server: &Server<B>) -> Option<IpAddr> {
+        self.peer_addr.map(|addr| client_key(addr.ip(), server.ipv6_prefix_len))
     }
 
     pub async fn handle(&mut self, cmd: String, arg: String, server: &Server<B>) {
+        let cmd = cmd.to_uppercase();
+        self.stream.get_mut().log.command = cmd.clone();
+        conn_log!(self.log(), Level::Debug; "command");
+        server.metrics.command(&cmd);
+
+        self.dispatch(cmd, arg, server).await;
+
+        self.stream.get_mut().log.command.clear();
+    }
+
+    async fn dispatch(&mut self, cmd: String, arg: String, server: &Server<B>) {
         if cmd.is_empty() {
-            self.protocol_error(500, [5, 5, 2], "Error: bad syntax".to_string())
+            self.protocol_error(500, [5, 5, 2], "Error: bad syntax".to_string(), server)
                 .await;
             return;
         }
 
-        let cmd = cmd.to_uppercase();
         match cmd.as_str() {
+            // The session is busy with the message, and would be replaced.
+            "HELO" | "EHLO" | "LHLO" | "STARTTLS" if self.bdat_pipe.is_some() => {
+                self.stream.get_mut().write_response(
+                    502,
+                    [5, 5, 1],
+                    &[&format!("{} not allowed during message transfer", cmd)],
+                )
+                .await;
+            }
             "SEND" | "SOML" | "SAML" | "EXPN" | "HELP" | "TURN" => {
                 self.stream.get_mut().write_response(
                     502,
@@ -87,9 +210,32 @@ impl<B: Backend> Conn<B> {
                 .await;
             }
             "HELO" | "EHLO" => {
+                if server.lmtp {
+                    self.protocol_error(
+                        500,
+                        [5, 5, 1],
+                        "This is a LMTP server, use LHLO".to_string(),
+                        server,
+                    )
+                    .await;
+                    return;
+                }
                 let enhanced = cmd == "EHLO";
                 self.handle_greet(enhanced, arg, server).await;
             }
+            "LHLO" => {
+                if !server.lmtp {
+                    self.protocol_error(
+                        500,
+                        [5, 5, 1],
+                        "This is not a LMTP server".to_string(),
+                        server,
+                    )
+                    .await;
+                    return;
+                }
+                self.handle_greet(true, arg, server).await;
+            }
             "MAIL" => {
                 self.handle_mail(arg, server).await;
             }
@@ -121,13 +267,8 @@ impl<B: Backend> Conn<B> {
             }
             "QUIT" => {
                 self.stream.get_mut().write_response(221, [2, 0, 0], &["Bye"]).await;
-                match self.close().await {
-                    Ok(_) => {
-                        println!("Connection successfully closed");
-                    }
-                    Err(e) => {
-                        println!("Error closing connection: {}", e);
-                    }
+                if let Err(err) = self.close().await {
+                    conn_log!(self.log(), Level::Warn; "error closing connection: {}", err);
                 }
             }
             "AUTH" => {
@@ -136,6 +277,7 @@ impl<B: Backend> Conn<B> {
                         500,
                         [5, 5, 2],
                         "Syntax error, AUTH command unrecognized".to_string(),
+                        server,
                     )
                     .await;
                 } else {
@@ -150,47 +292,128 @@ impl<B: Backend> Conn<B> {
                     500,
                     [5, 5, 2],
                     format!("Syntax errors, {} command unrecognized", cmd),
+                    server,
                 )
                 .await;
             }
         }
     }
 
-    pub async fn protocol_error(&mut self, code: u16, ec: EnhancedCode, msg: String) {
-        self.stream.get_mut().write_response(code, ec, &[&msg]).await;
+    /// Replies to a bad command. Replies are delayed longer after each error
+    /// when tarpitting is on, and the connection is dropped once the client
+    /// makes `Server::max_errors` errors.
+    pub async fn protocol_error(&mut self, code: u16, ec: EnhancedCode, msg: String, server: &Server<B>) {
         self.err_count += 1;
+        if server.max_errors > 0 && self.err_count >= server.max_errors {
+            conn_log!(self.log(), Level::Info, errors = self.err_count; "too many errors");
+            self.stream.get_mut().write_response(421, [4, 7, 0], &["Too many errors"]).await;
+            let _ = self.close().await;
+            return;
+        }
+
+        self.tarpit(server).await;
+        self.stream.get_mut().write_response(code, ec, &[&msg]).await;
     }
 
-    pub async fn close(&mut self) -> Result<()> {
+    /// Sleeps before replying to an error or a rejected recipient, longer
+    /// after each one, to slow down bots and dictionary attacks.
+    async fn tarpit(&mut self, server: &Server<B>) {
+        let penalties = (self.err_count + self.rejected_rcpts) as u32;
+        if server.tarpit_delay.is_zero() || penalties == 0 {
+            return;
+        }
+
+        let mut delay = server.tarpit_delay.saturating_mul(penalties);
+        if !server.tarpit_max_delay.is_zero() {
+            delay = delay.min(server.tarpit_max_delay);
+        }
+        tokio::time::sleep(delay).await;
+    }
 
+    pub async fn close(&mut self) -> Result<()> {
+        self.abort_transfer().await;
         if let Some(mut session) = self.session.take() {
             let _ = session.logout();
         }
 
         let _ = self.stream.get_mut().close().await;
-
-        if let Some(pipe) = &mut self.bdat_pipe.take() {
-            let _ = pipe.shutdown().await;
-        }
         self.bytes_received = 0;
 
         Ok(())
     }
 
+    /// Ends a BDAT transfer in progress: the session's reader fails, and the
+    /// session is handed back once it gave up on the message.
+    async fn abort_transfer(&mut self) {
+        self.bdat_pipe = None;
+        if let Some(mut join_handle) = self.data_result.take() {
+            match tokio::time::timeout(ABORTED_TRANSFER_TIMEOUT, &mut join_handle).await {
+                Ok(Ok((_, session, _))) => self.session = Some(session),
+                Ok(Err(err)) => conn_log!(self.log(), Level::Error; "message transfer task failed: {}", err),
+                Err(_) => {
+                    conn_log!(self.log(), Level::Warn; "session did not give up on an aborted message");
+                    join_handle.abort();
+                }
+            }
+        }
+    }
+
+    /// Whether a BDAT message transfer is in progress.
+    pub fn is_transferring(&self) -> bool {
+        self.bdat_pipe.is_some()
+    }
+
+    /// Whether the connection has been closed, e.g. after QUIT.
+    pub fn is_closed(&self) -> bool {
+        self.stream.get_ref().is_closed()
+    }
+
     pub fn hostname(&self) -> String {
         self.helo.clone()
     }
 
+    /// The address of the client, or `None` on Unix domain sockets.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// The address the client connected to, or `None` on Unix domain
+    /// sockets.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// The credentials (uid, gid and pid) of the process on the other end of
+    /// a Unix domain socket connection.
+    #[cfg(unix)]
+    pub fn peer_cred(&self) -> Option<UCred> {
+        self.peer_cred
+    }
+
     pub fn auth_allowed(&self, server: &Server<B>) -> bool {
         !self.auths.is_empty() && (self.stream.get_ref().is_tls() || server.allow_insecure_auth)
     }
 
     pub async fn handle_greet(&mut self, enhanced: bool, arg: String, server: &Server<B>) {
-        self.helo = arg;
+        // Internationalized domains are kept in their A-label form.
+        self.helo = if arg.is_ascii() {
+            arg
+        } else {
+            match domain_to_ascii(&arg) {
+                Ok(domain) => domain,
+                Err(_) => {
+                    self.stream.get_mut().write_response(501, [5, 5, 2], &["Invalid domain name"])
+                        .await;
+                    return;
+                }
+            }
+        };
+        self.tracker.set_helo(&self.helo);
 
         match server.backend.new_session(self) {
             Err(err) => {
-                self.stream.get_mut().write_response(451, [4, 0, 0], &[&err.to_string()])
+                let (code, ec, msg) = error_status(&err, 451, [4, 0, 0]);
+                self.stream.get_mut().write_response(code, ec, &[&msg])
                     .await;
                 return;
             }
@@ -212,14 +435,14 @@ impl<B: Backend> Conn<B> {
 
         let mut caps = server.caps.clone();
 
-        if server.tls_acceptor.is_some() && !self.stream.get_ref().is_tls() {
+        if server.tls_acceptor.is_some() && self.stream.get_ref().can_starttls() {
             caps.push("STARTTLS".to_string());
         }
 
         if self.auth_allowed(server) {
             let mut auth_cap = "AUTH".to_string();
             for name in self.auths.keys() {
-                auth_cap.push_str(" ");
+                auth_cap.push(' ');
                 auth_cap.push_str(name);
             }
 
@@ -231,9 +454,13 @@ impl<B: Backend> Conn<B> {
         if server.enable_requiretls && self.stream.get_ref().is_tls() {
             caps.push("REQUIRETLS".to_string());
         }
-        if server.enable_binarymime {
+        // BINARYMIME messages can only be sent with BDAT.
+        if server.enable_binarymime && server.caps.iter().any(|cap| cap == "CHUNKING") {
             caps.push("BINARYMIME".to_string());
         }
+        if server.enable_dsn {
+            caps.push("DSN".to_string());
+        }
 
         if server.max_message_bytes > 0 {
             caps.push(format!("SIZE {}", server.max_message_bytes));
@@ -254,7 +481,7 @@ impl<B: Backend> Conn<B> {
     }
 
     pub async fn handle_mail(&mut self, arg: String, server: &Server<B>) {
-        if self.helo.len() == 0 {
+        if self.helo.is_empty() {
             self.stream.get_mut().write_response(502, [2, 5, 1], &["Please introduce yourself first."])
                 .await;
             return;
@@ -268,19 +495,20 @@ impl<B: Backend> Conn<B> {
             .await;
             return;
         }
-
-        if arg.len() < 6 || arg[0..5].to_uppercase() != "FROM:" {
+        if server.max_messages_per_connection > 0 && self.messages >= server.max_messages_per_connection {
             self.stream.get_mut().write_response(
-                501,
-                [5, 5, 2],
-                &["Was expecting MAIL arg syntax of FROM:<address>"],
+                421,
+                [4, 7, 0],
+                &["Too many messages for this session, try again later"],
             )
             .await;
+            let _ = self.close().await;
             return;
         }
-        let from_args = arg[5..].trim().split(' ').collect::<Vec<&str>>();
-        if server.strict {
-            if !from_args[0].starts_with('<') || !from_args[0].ends_with('>') {
+
+        let path = match arg.get(0..5) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("FROM:") => arg[5..].trim_start(),
+            _ => {
                 self.stream.get_mut().write_response(
                     501,
                     [5, 5, 2],
@@ -289,24 +517,21 @@ impl<B: Backend> Conn<B> {
                 .await;
                 return;
             }
-        }
-        if from_args.len() == 0 || from_args[0].len() < 3 {
-            self.stream.get_mut().write_response(
-                501,
-                [5, 5, 2],
-                &["Was expecting MAIL arg syntax of FROM:<address>"],
-            )
-            .await;
-            return;
-        }
-        let from = &from_args[0][1..from_args[0].len() - 1];
+        };
+        let (from, params) = match parse_reverse_path(path, server.strict) {
+            Ok(v) => v,
+            Err(_) => {
+                self.stream.get_mut().write_response(501, [5, 1, 7], &["Bad sender address syntax"])
+                    .await;
+                return;
+            }
+        };
+        let from_args = params.split_whitespace().collect::<Vec<&str>>();
 
         let mut opts = MailOptions::new();
 
-        self.binarymime = false;
-
-        if from_args.len() > 1 {
-            let args = parse_args(&from_args[1..]);
+        if !from_args.is_empty() {
+            let args = parse_args(&from_args);
             if args.is_err() {
                 self.stream.get_mut().write_response(501, [5, 5, 4], &["Unable to parse MAIL ESMTP parameters"])
                     .await;
@@ -356,34 +581,43 @@ impl<B: Backend> Conn<B> {
                                 .await;
                             return;
                         }
+                        if !value.is_empty() {
+                            self.stream.get_mut().write_response(501, [5, 5, 4], &["REQUIRETLS takes no value"])
+                                .await;
+                            return;
+                        }
+                        // The parameter is only advertised over TLS, and it
+                        // means nothing over a connection without it.
+                        if !self.stream.get_ref().is_tls() {
+                            self.stream.get_mut().write_response(530, [5, 7, 10], &["REQUIRETLS requires a TLS connection"])
+                                .await;
+                            return;
+                        }
                         opts.require_tls = true;
                     }
 
                     "BODY" => {
-                        match value.as_str() {
+                        opts.body = match value.to_uppercase().as_str() {
+                            "7BIT" => BodyType::SevenBit,
+                            "8BITMIME" => BodyType::EightBitMime,
                             "BINARYMIME" => {
-                                if !server.enable_binarymime {
-                                    self.stream.get_mut().write_response(
-                                        501,
-                                        [5, 5, 4],
-                                        &["BINARYMIME is not implemented"],
-                                    )
-                                    .await;
+                                if !server.enable_binarymime || !server.caps.iter().any(|cap| cap == "CHUNKING") {
+                                    self.stream.get_mut().write_response(504, [5, 5, 4], &["BINARYMIME is not implemented"])
+                                        .await;
                                     return;
                                 }
+                                BodyType::BinaryMime
                             }
-                            "7BIT" | "8BITMIME" => {}
                             _ => {
                                 self.stream.get_mut().write_response(500, [5, 5, 4], &["Unknown BODY value"])
                                     .await;
                                 return;
                             }
-                        }
-                        opts.body = value;
+                        };
                     }
 
                     "AUTH" => {
-                        let value = decode_xtext(value);
+                        let value = decode_xtext(&value);
                         if value.is_err() {
                             self.stream.get_mut().write_response(
                                 500,
@@ -408,8 +642,41 @@ impl<B: Backend> Conn<B> {
                         opts.auth = decoded_mbox;
                     }
 
+                    "RET" => {
+                        if !server.enable_dsn {
+                            self.stream.get_mut().write_response(555, [5, 5, 4], &["DSN is not implemented"])
+                                .await;
+                            return;
+                        }
+                        opts.ret = match value.to_uppercase().as_str() {
+                            "FULL" => Some(DSNReturn::Full),
+                            "HDRS" => Some(DSNReturn::Headers),
+                            _ => {
+                                self.stream.get_mut().write_response(501, [5, 5, 4], &["Unknown RET value"])
+                                    .await;
+                                return;
+                            }
+                        };
+                    }
+
+                    "ENVID" => {
+                        if !server.enable_dsn {
+                            self.stream.get_mut().write_response(555, [5, 5, 4], &["DSN is not implemented"])
+                                .await;
+                            return;
+                        }
+                        match decode_xtext(&value) {
+                            Ok(envelope_id) if value.len() <= MAX_ENVID_LEN => opts.envelope_id = Some(envelope_id),
+                            _ => {
+                                self.stream.get_mut().write_response(501, [5, 5, 4], &["Malformed ENVID parameter value"])
+                                    .await;
+                                return;
+                            }
+                        }
+                    }
+
                     _ => {
-                        self.stream.get_mut().write_response(500, [5, 5, 4], &["Unknown MAIL FROM argument"])
+                        self.stream.get_mut().write_response(555, [5, 5, 4], &["Unknown MAIL FROM argument"])
                             .await;
                         return;
                     }
@@ -417,19 +684,33 @@ impl<B: Backend> Conn<B> {
             }
         }
 
-        if self.session.is_none() {
-            self.stream.get_mut().write_response(502, [5, 5, 1], &["Wrong sequence of commands"])
+        if !from.is_ascii() && !opts.utf8 {
+            self.stream.get_mut().write_response(553, [5, 6, 7], &["Non-ASCII addresses require SMTPUTF8"])
                 .await;
             return;
-        } else {
-            if let Err(err) = self.session.as_mut().unwrap().mail(from, &opts).await {
-                self.stream.get_mut().write_response(451, [4, 0, 0], &[&err.to_string()])
+        }
+
+        match self.session.as_mut() {
+            None => {
+                self.stream.get_mut().write_response(502, [5, 5, 1], &["Wrong sequence of commands"])
                     .await;
                 return;
             }
+            Some(session) => {
+                if let Err(err) = session.mail(&from, &opts).await {
+                    let (code, ec, msg) = error_status(&err, 451, [4, 0, 0]);
+                    self.stream.get_mut().write_response(code, ec, &[&msg])
+                        .await;
+                    return;
+                }
+            }
         }
         self.stream.get_mut().write_response(250, [2, 0, 0], &["OK"]).await;
         self.from_received = true;
+        self.utf8 = opts.utf8;
+        self.binarymime = opts.body == BodyType::BinaryMime;
+        self.messages += 1;
+        self.tracker.set_state(TransactionState::Mail);
     }
 
     pub async fn reject(&mut self) {
@@ -438,6 +719,14 @@ impl<B: Backend> Conn<B> {
         let _ = self.close().await;
     }
 
+    /// Turns the client away before the greeting because it connects too
+    /// often.
+    pub async fn reject_rate_limited(&mut self) {
+        self.stream.get_mut().write_response(421, [4, 7, 0], &["Too many connections, slow down"])
+            .await;
+        let _ = self.close().await;
+    }
+
     pub async fn greet(&mut self, domain: String) {
         self.stream.get_mut().write_response(
             220,
@@ -447,14 +736,36 @@ impl<B: Backend> Conn<B> {
         .await;
     }
 
-    pub async fn read_line(&mut self, mut line: &mut String, server: &Server<B>) -> Result<usize> {
-        let res = timeout(server.read_timeout, self.stream.read_line(&mut line)).await?;
-        res.map_err(|e| anyhow!(e))
+    /// Reads a command line. If the client sends nothing for `timeout`, a
+    /// `tokio::time::error::Elapsed` error is returned. A zero `timeout`
+    /// means no timeout. Lines longer than the connection's
+    /// `max_line_length` are skipped and reported as `TooLongLine`.
+    pub async fn read_line(&mut self, line: &mut String, timeout: Duration) -> Result<usize> {
+        with_timeout(timeout, async {
+            let mut r = LineLimitReader::new(&mut self.stream, self.max_line_length);
+            let mut buf = Vec::new();
+            let res = r.read_until(b'\n', &mut buf).await;
+            if r.too_long {
+                skip_line(&mut self.stream).await?;
+                return Err(TooLongLine.into());
+            }
+            let n = res?;
+            line.push_str(std::str::from_utf8(&buf).map_err(|_| InvalidUtf8)?);
+            Ok(n)
+        })
+        .await?
+    }
+
+    /// Ends the session after the client, or the backend, took too long in
+    /// `phase`.
+    pub(crate) async fn timed_out(&mut self, phase: &'static str) {
+        conn_log!(self.log(), Level::Info, phase = phase; "timeout");
+        self.stream.get_mut().write_response(421, [4, 4, 2], &["Timeout exceeded, closing connection"]).await;
+        let _ = self.close().await;
     }
 
     // MAIL state -> waiting for RCPTs followed by DATA
     pub async fn handle_rcpt(&mut self, arg: String, server: &Server<B>) {
-        let arg = arg.to_uppercase();
         if !self.from_received {
             self.stream.get_mut().write_response(502, [5, 5, 1], &["Missing MAIL FROM command"])
                 .await;
@@ -470,21 +781,77 @@ impl<B: Backend> Conn<B> {
             return;
         }
 
-        if arg.len() < 4 || !arg.starts_with("TO:") {
-            self.stream.get_mut().write_response(
-                501,
-                [5, 5, 2],
-                &["Was expecting RCPT arg syntax of TO:<address>"],
-            )
-            .await;
+        let path = match arg.get(0..3) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("TO:") => arg[3..].trim_start(),
+            _ => {
+                self.stream.get_mut().write_response(
+                    501,
+                    [5, 5, 2],
+                    &["Was expecting RCPT arg syntax of TO:<address>"],
+                )
+                .await;
+                return;
+            }
+        };
+        let (recipient, params) = match parse_forward_path(path, server.strict) {
+            Ok(v) => v,
+            Err(_) => {
+                self.stream.get_mut().write_response(501, [5, 1, 3], &["Bad recipient address syntax"])
+                    .await;
+                return;
+            }
+        };
+        if !recipient.is_ascii() && !self.utf8 {
+            self.stream.get_mut().write_response(553, [5, 6, 7], &["Non-ASCII addresses require SMTPUTF8"])
+                .await;
             return;
         }
 
-        let recipient = arg[3..]
-            .trim_start_matches('<')
-            .trim_end_matches('>')
-            .trim()
-            .to_lowercase();
+        let mut opts = RcptOptions::new();
+
+        let rcpt_args = params.split_whitespace().collect::<Vec<&str>>();
+        if !rcpt_args.is_empty() {
+            let args = parse_args(&rcpt_args);
+            if args.is_err() {
+                self.stream.get_mut().write_response(501, [5, 5, 4], &["Unable to parse RCPT ESMTP parameters"])
+                    .await;
+                return;
+            }
+
+            for (key, value) in args.unwrap() {
+                match key.as_str() {
+                    "NOTIFY" | "ORCPT" if !server.enable_dsn => {
+                        self.stream.get_mut().write_response(555, [5, 5, 4], &["DSN is not implemented"])
+                            .await;
+                        return;
+                    }
+
+                    "NOTIFY" => match parse_notify(&value) {
+                        Ok(notify) => opts.notify = notify,
+                        Err(_) => {
+                            self.stream.get_mut().write_response(501, [5, 5, 4], &["Malformed NOTIFY parameter value"])
+                                .await;
+                            return;
+                        }
+                    },
+
+                    "ORCPT" => match parse_orcpt(&value) {
+                        Ok(orcpt) => opts.original_recipient = Some(orcpt),
+                        Err(_) => {
+                            self.stream.get_mut().write_response(501, [5, 5, 4], &["Malformed ORCPT parameter value"])
+                                .await;
+                            return;
+                        }
+                    },
+
+                    _ => {
+                        self.stream.get_mut().write_response(555, [5, 5, 4], &["Unknown RCPT TO argument"])
+                            .await;
+                        return;
+                    }
+                }
+            }
+        }
 
         if server.max_recipients > 0 && self.recipients.len() >= server.max_recipients {
             self.stream.get_mut().write_response(
@@ -499,19 +866,41 @@ impl<B: Backend> Conn<B> {
             return;
         }
 
-        if self.session.is_none() {
-            self.stream.get_mut().write_response(502, [5, 5, 1], &["Wrong sequence of commands"])
-                .await;
-            return;
-        } else {
-            if let Err(err) = self.session.as_mut().unwrap().rcpt(&recipient).await {
-                self.stream.get_mut().write_response(451, [4, 0, 0], &[&err.to_string()])
+        if let (Some(limiter), Some(key)) = (&server.rate_limiter, self.client_key(server)) {
+            if !limiter.allow_recipient(key).await {
+                // Ask for the first recipient to be retried later, and for the
+                // remaining ones to go in another transaction.
+                if self.recipients.is_empty() {
+                    self.stream.get_mut().write_response(450, [4, 7, 1], &["Recipient rate limit exceeded, try again later"])
+                        .await;
+                } else {
+                    self.stream.get_mut().write_response(452, [4, 5, 3], &["Too many recipients for now, try again later"])
+                        .await;
+                }
+                return;
+            }
+        }
+
+        match self.session.as_mut() {
+            None => {
+                self.stream.get_mut().write_response(502, [5, 5, 1], &["Wrong sequence of commands"])
                     .await;
                 return;
             }
+            Some(session) => {
+                if let Err(err) = session.rcpt(&recipient, &opts).await {
+                    self.rejected_rcpts += 1;
+                    self.tarpit(server).await;
+                    let (code, ec, msg) = error_status(&err, 451, [4, 0, 0]);
+                    self.stream.get_mut().write_response(code, ec, &[&msg])
+                        .await;
+                    return;
+                }
+            }
         }
 
-        self.recipients.push(recipient);
+        self.recipients.push(recipient.to_string());
+        self.tracker.set_state(TransactionState::Rcpt);
         self.stream.get_mut().write_response(250, [2, 0, 0], &["OK"]).await;
     }
 
@@ -551,11 +940,9 @@ impl<B: Backend> Conn<B> {
         // Parse client initial response if there is one
         let mut ir = Vec::new();
         if parts.len() > 1 {
-            let res = general_purpose::STANDARD.decode(parts[1]);
-            if res.is_err() {
-                return;
-            } else {
-                ir = res.unwrap();
+            match general_purpose::STANDARD.decode(parts[1]) {
+                Ok(res) => ir = res,
+                Err(_) => return,
             }
         }
 
@@ -564,13 +951,14 @@ impl<B: Backend> Conn<B> {
                 .await;
             return;
         }
-        let sasl = self.auths.get_mut(&mechanism).unwrap();
-
         let mut response = ir;
         loop {
+            let sasl = self.auths.get_mut(&mechanism).unwrap();
             let res = sasl.next(Some(&response)).await;
             if let Err(err) = res {
-                self.stream.get_mut().write_response(454, [4, 7, 0], &[&err.to_string()])
+                server.metrics.auth(false);
+                let (code, ec, msg) = error_status(&err, 454, [4, 7, 0]);
+                self.stream.get_mut().write_response(code, ec, &[&msg])
                     .await;
                 return;
             }
@@ -587,16 +975,21 @@ impl<B: Backend> Conn<B> {
 
             self.stream.get_mut().write_response(334, NO_ENHANCED_CODE, &[&encoded]).await;
 
-            let encoded = &mut String::new();
-            let res = timeout(server.read_timeout, self.stream.read_line(encoded)).await;
-            if res.is_err() {
-                self.stream.get_mut().write_response(454, [4, 7, 0], &["Read timeout"]).await;
-                return;
-            }
-            let res = res.unwrap();
-            if res.is_err() {
-                self.stream.get_mut().write_response(454, [4, 7, 0], &["Read error"]).await;
-                return;
+            let mut encoded = String::new();
+            match self.read_line(&mut encoded, server.read_timeout).await {
+                Ok(_) => {}
+                Err(err) if err.is::<Elapsed>() => {
+                    self.timed_out("command").await;
+                    return;
+                }
+                Err(err) if err.is::<TooLongLine>() => {
+                    self.protocol_error(500, [5, 5, 2], "Line too long".to_string(), server).await;
+                    return;
+                }
+                Err(_) => {
+                    self.stream.get_mut().write_response(454, [4, 7, 0], &["Read error"]).await;
+                    return;
+                }
             }
             let encoded = encoded.trim_end();
 
@@ -606,7 +999,7 @@ impl<B: Backend> Conn<B> {
                 return;
             }
 
-            let res = general_purpose::STANDARD.decode(&encoded);
+            let res = general_purpose::STANDARD.decode(encoded);
             if res.is_err() {
                 self.stream.get_mut().write_response(454, [4, 7, 0], &["Invalid base64 data"]).await;
                 return;
@@ -615,7 +1008,9 @@ impl<B: Backend> Conn<B> {
         }
 
         self.stream.get_mut().write_response(235, [2,0,0], &["Authentication succeeded"]).await;
+        server.metrics.auth(true);
         self.did_auth = true;
+        self.tracker.set_auth_identity(self.auths[&mechanism].identity());
     }
 
     pub async fn handle_starttls(&mut self, server: &Server<B>) {
@@ -624,34 +1019,50 @@ impl<B: Backend> Conn<B> {
             return;
         }
 
-        if server.tls_acceptor.is_none() {
+        if server.tls_acceptor.is_none() || !self.stream.get_ref().can_starttls() {
             self.stream.get_mut().write_response(502, [5, 5, 1], &["TLS not supported"]).await;
             return;
         }
 
         self.stream.get_mut().write_response(220, [2, 0, 0], &["Ready to start TLS"]).await;
 
-        if self.stream.get_ref().unsafe_stream.is_none() {
+        if !self.stream.get_ref().can_starttls() {
             self.stream.get_mut().write_response(550, [5, 0, 0], &["Handshake error"]).await;
             return;
         }
 
-        if let Err(_) = self.stream.get_mut().starttls(server.tls_acceptor.clone().unwrap()).await {
-            self.stream.get_mut().write_response(550, [5, 0, 0], &["Handshake error"]).await;
-            return;
+        let acceptor = server.tls_acceptor.clone().unwrap();
+        match self.stream.get_mut().starttls(acceptor, server.tls_handshake_timeout).await {
+            Ok(()) => {}
+            Err(err) if err.is::<Elapsed>() => {
+                conn_log!(self.log(), Level::Info; "TLS handshake timeout");
+                let _ = self.close().await;
+                return;
+            }
+            // The stream went with the failed handshake, so there is no one
+            // left to reply to.
+            Err(err) => {
+                conn_log!(self.log(), Level::Warn; "TLS handshake error: {}", err);
+                let _ = self.close().await;
+                return;
+            }
         }
 
         if let Some(mut session) = self.session.take() {
             let _ = session.logout();
         }
 
+        server.metrics.tls_upgrade();
         self.helo = "".to_string();
         self.did_auth = false;
+        self.tracker.set_tls(true);
+        self.tracker.set_helo("");
+        self.tracker.set_auth_identity(None);
         self.reset().await;
     }
 
     pub async fn handle_data(&mut self, arg: String, server: &Server<B>) {
-        if arg.len() > 0 {
+        if !arg.is_empty() {
             self.stream.get_mut().write_response(
                 501,
                 [5, 5, 4],
@@ -671,7 +1082,7 @@ impl<B: Backend> Conn<B> {
         }
         if self.binarymime {
             self.stream.get_mut().write_response(
-                502,
+                503,
                 [5, 5, 1],
                 &["DATA not allowed for BINARYMIME messages"],
             )
@@ -690,146 +1101,232 @@ impl<B: Backend> Conn<B> {
             &["Go ahead. End your data with <CR><LF>.<CR><LF>"],
         )
         .await;
-
-        let mut r = DataReader::new(
-            &mut self.stream,
-            server.max_message_bytes,
+        self.tracker.set_state(TransactionState::Data);
+
+        let mut lr = LineLimitReader::new(&mut self.stream, self.max_line_length);
+        let mut r = TimedReader::new(
+            DataReader::new(
+                &mut lr,
+                server.max_message_bytes,
+            ),
+            server.data_block_timeout,
         );
+        let idle = r.idle();
+
+        let mut status = StatusCollector::new(self.recipients.clone());
+        let session = self.session.as_mut().unwrap();
+        let res = tokio::select! {
+            res = async {
+                if server.lmtp {
+                    session.lmtp_data(&mut r, &mut status).await
+                } else {
+                    session.data(&mut r).await
+                }
+            } => Some(res),
+            // The session gets the termination timeout whenever it keeps the
+            // client waiting, not only once it read the whole message.
+            _ = after_idle(idle, server.data_termination_timeout) => None,
+        };
 
-        let res = self
-            .session
-            .as_mut()
-            .unwrap()
-            .data(&mut r)
-            .await;
+        // Whatever the session left unread is discarded, whatever its size
+        // or the length of its lines, so that the rest of the message isn't
+        // taken for commands.
+        let mut drained = Ok(0);
+        if res.is_some() && !r.timed_out {
+            r.get_mut().remove_limit();
+            drained = drain_data(&mut r).await;
+        }
 
-        r.limited = false;
-        // Make sure all the data has been consumed and discarded
-        //let _ = r.read_to_end(&mut Vec::new()).await;
+        let timed_out = r.timed_out;
+        let r = r.into_inner();
+        let too_large = r.too_large();
+        let size = r.count();
+        let too_long = lr.too_long;
 
-        drop(r);
+        if timed_out {
+            self.timed_out("data block").await;
+            return;
+        }
+        let mut res = match res {
+            Some(res) => res,
+            None => {
+                self.timed_out("data termination").await;
+                return;
+            }
+        };
+        let drained = match drained {
+            Ok(drained) => drained,
+            Err(err) => {
+                conn_log!(self.log(), Level::Warn; "error reading DATA: {}", err);
+                let _ = self.close().await;
+                return;
+            }
+        };
 
-        if res.is_ok() {
-            self.stream.get_mut().write_response(250, [2, 0, 0], &["OK"]).await;
-        } else {
-            self.stream.get_mut().write_response(554, [5, 0, 0], &[&res.err().unwrap().to_string()])
-                .await;
+        if drained > MAX_DRAIN_BYTES {
+            // The rest of the message is still on its way, so there can't be
+            // a final reply to it yet.
+            conn_log!(self.log(), Level::Info; "DATA left unread too long, closing connection");
+            self.stream.get_mut().write_response(421, [4, 3, 0], &["Too much unread data, closing connection"]).await;
+            let _ = self.close().await;
+            return;
         }
 
+        // The session only saw part of the message, so its outcome doesn't
+        // apply.
+        if too_long {
+            conn_log!(self.log(), Level::Info; "DATA line too long");
+            res = Err(SMTPError::new(554, [5, 6, 0], "Line too long").into());
+            status = StatusCollector::new(self.recipients.clone());
+        } else if too_large {
+            res = Err(SMTPError::new(552, [5, 3, 4], "Max message size exceeded").into());
+            status = StatusCollector::new(self.recipients.clone());
+        }
+        self.write_data_replies(res, status, size, server).await;
+
         self.reset().await;
     }
 
-    pub async fn handle_bdat(&mut self, arg: String, server: &Server<B>) {
-        let args: Vec<&str> = arg.split_whitespace().collect();
-        if args.is_empty() {
-            println!("Missing chunk size argument");
-            self.stream.get_mut().write_response(501, [5, 5, 4], &["Missing chunk size argument"])
-                .await;
+    /// Writes the final reply of a DATA or BDAT transaction of `size` bytes:
+    /// a single reply for SMTP, or one reply per accepted recipient for LMTP.
+    async fn write_data_replies(&mut self, res: Result<()>, status: StatusCollector, size: usize, server: &Server<B>) {
+        if !server.lmtp {
+            if res.is_ok() {
+                server.metrics.message_accepted(size);
+            }
+            let (code, ec, msg) = data_status(&res);
+            self.stream.get_mut().write_response(code, ec, &[&msg]).await;
             return;
         }
-        if args.len() > 2 {
-            println!("Too many arguments");
-            self.stream.get_mut().write_response(501, [5, 5, 4], &["Too many arguments"])
-                .await;
-            return;
+
+        let mut accepted = false;
+        for (_, rcpt_res) in status.into_statuses() {
+            let (code, ec, msg) = match &rcpt_res {
+                Some(rcpt_res) => data_status(rcpt_res),
+                None => data_status(&res),
+            };
+            accepted |= code < 400;
+            self.stream.get_mut().write_response(code, ec, &[&msg]).await;
         }
+        if accepted {
+            server.metrics.message_accepted(size);
+        }
+    }
 
-        if !self.from_received || self.recipients.is_empty() {
-            println!("Missing RCPT TO command");
-            self.stream.get_mut().write_response(502, [5, 5, 1], &["Missing RCPT TO command."])
+    pub async fn handle_bdat(&mut self, arg: String, server: &Server<B>) {
+        let args: Vec<&str> = arg.split_whitespace().collect();
+        if args.is_empty() {
+            self.stream.get_mut().write_response(501, [5, 5, 4], &["Missing chunk size argument"])
                 .await;
             return;
         }
 
-        let mut last = false;
-        if args.len() == 2 {
-            if args[1].to_lowercase() != "last" {
-                println!("Unknown BDAT argument");
-                self.stream.get_mut().write_response(501, [5, 5, 4], &["Unknown BDAT argument"])
-                    .await;
-                return;
-            }
-            last = true;
-        }
-
         let size = match args[0].parse::<usize>() {
             Ok(size) => size,
             Err(_) => {
-                println!("Malformed size argument");
                 self.stream.get_mut().write_response(501, [5, 5, 4], &["Malformed size argument"])
                     .await;
                 return;
             }
         };
 
-        if server.max_message_bytes != 0 && self.bytes_received + size > server.max_message_bytes {
-            println!("Max message size exceeded");
-            self.stream.get_mut().write_response(552, [5, 3, 4], &["Max message size exceeded"])
-                .await;
-
-            let _ = self.stream.get_mut().read_to_end(&mut Vec::new()).await;
+        // The chunk follows the command whatever the reply, so a rejected
+        // chunk is still read and discarded.
+        let mut last = false;
+        let mut rejection = None;
+        if args.len() > 2 {
+            rejection = Some((501, [5, 5, 4], "Too many arguments"));
+        } else if args.len() == 2 {
+            if args[1].eq_ignore_ascii_case("LAST") {
+                last = true;
+            } else {
+                rejection = Some((501, [5, 5, 4], "Unknown BDAT argument"));
+            }
+        }
+        if rejection.is_none() && (!self.from_received || self.recipients.is_empty()) {
+            rejection = Some((502, [5, 5, 1], "Missing RCPT TO command."));
+        }
+        if rejection.is_none()
+            && server.max_message_bytes != 0
+            && self.bytes_received.saturating_add(size) > server.max_message_bytes
+        {
+            rejection = Some((552, [5, 3, 4], "Max message size exceeded"));
+        }
 
-            self.reset().await;
+        if let Some((code, ec, msg)) = rejection {
+            if let Err(err) = copy_chunk(&mut self.stream, size, server.data_block_timeout, server.data_termination_timeout, None).await {
+                self.chunk_failed(err).await;
+                return;
+            }
+            self.reject_chunk(last, code, ec, msg, server).await;
+            // The message can't be completed any more: a failed chunk fails
+            // the transaction, and the chunks pipelined after it are
+            // discarded (RFC 3030).
+            if code == 552 || self.bdat_pipe.is_some() {
+                self.reset().await;
+            }
             return;
         }
 
         if self.bdat_pipe.is_none() {
-            // create duplexstream pipe
-            let (tx, rx) = io::duplex(size);
-            self.bdat_pipe = Some(tx);
+            self.tracker.set_state(TransactionState::Bdat);
 
-            //let fut = self.session.as_mut().unwrap().data(rx);
+            let (tx, rx) = bdat::pipe();
+            self.bdat_pipe = Some(tx);
 
             let mut session = self.session.take().unwrap();
+            let mut status = StatusCollector::new(self.recipients.clone());
+            let lmtp = server.lmtp;
 
             self.data_result = Some(tokio::spawn(async move {
-                let res = session.data(rx).await;
+                let res = if lmtp {
+                    session.lmtp_data(rx, &mut status).await
+                } else {
+                    session.data(rx).await
+                };
 
-                return (res, session);
+                (res, session, status)
             }));
         }
 
-        //self.line_limit_reader.line_limit = 0;
-
-        //let mut limit_reader = self.text.conn.clone().take(size as u64);
-        let mut pipe = self.bdat_pipe.as_mut().unwrap();
+        conn_log!(self.log(), Level::Debug, size = size; "reading BDAT chunk");
 
-        let mut buf = vec![0; size];
-
-        println!("I'm about to read {} bytes", size);
-
-        self.stream.read_exact(&mut buf).await.unwrap();
-
-        let res = io::copy(&mut (&buf[..]), &mut pipe).await;
-        if let Err(err) = res {
-            // discard the rest of the message
-            let _ = io::copy(&mut self.stream, &mut io::sink()).await;
-
-            self.stream.get_mut().write_response(554, [5, 0, 0], &[&err.to_string()])
-                .await;
+        let pipe = self.bdat_pipe.as_mut();
+        let written = match copy_chunk(&mut self.stream, size, server.data_block_timeout, server.data_termination_timeout, pipe).await {
+            Ok(written) => written,
+            Err(err) => {
+                self.chunk_failed(err).await;
+                return;
+            }
+        };
+        self.bytes_received += size;
 
+        if !written {
+            // The session stopped reading before the end of the message,
+            // which only makes sense if it failed.
+            self.bdat_pipe = None;
+            let Some((res, session, _)) = self.finish_transfer(last, server).await else {
+                return;
+            };
+            self.session = Some(session);
+            let (code, ec, msg) = match res {
+                Ok(()) => (554, [5, 0, 0], "Message transfer aborted".to_string()),
+                Err(err) => error_status(&err, 554, [5, 0, 0]),
+            };
+            self.reject_chunk(last, code, ec, &msg, server).await;
             self.reset().await;
-            //self.line_limit_reader.line_limit = server.max_line_length;
             return;
         }
 
-        self.bytes_received += size;
-
         if last {
-            //self.line_limit_reader.line_limit = server.max_line_length;
-
-            let _ = self.bdat_pipe.as_mut().unwrap().shutdown().await;
-
-            if let Some(join_handle) = self.data_result.take() {
-                let (res, session) = join_handle.await.unwrap();
-                if res.is_ok() {
-                    self.stream.get_mut().write_response(250, [2, 0, 0], &["OK"]).await;
-                } else {
-                    self.stream.get_mut().write_response(554, [5, 0, 0], &[&res.err().unwrap().to_string()])
-                        .await;
-                }
-                self.session = Some(session);
+            if let Some(pipe) = self.bdat_pipe.take() {
+                pipe.finish().await;
             }
+            let Some((res, session, status)) = self.finish_transfer(last, server).await else {
+                return;
+            };
+            self.write_data_replies(res, status, self.bytes_received, server).await;
+            self.session = Some(session);
 
             self.reset().await;
         } else {
@@ -837,11 +1334,54 @@ impl<B: Backend> Conn<B> {
         }
     }
 
-    pub async fn reset(&mut self) {
-        if let Some(pipe) = self.bdat_pipe.as_mut() {
-            let _ = pipe.shutdown().await;
-            self.bdat_pipe = None;
+    /// Replies to a BDAT chunk which was rejected. The LAST chunk of an LMTP
+    /// transaction gets the reply once per recipient, as the client expects.
+    async fn reject_chunk(&mut self, last: bool, code: u16, ec: EnhancedCode, msg: &str, server: &Server<B>) {
+        if last && server.lmtp && !self.recipients.is_empty() {
+            let status = StatusCollector::new(self.recipients.clone());
+            let res = Err(SMTPError::new(code, ec, msg).into());
+            self.write_data_replies(res, status, self.bytes_received, server).await;
+        } else {
+            self.stream.get_mut().write_response(code, ec, &[msg]).await;
         }
+    }
+
+    /// Waits for the session to be done with a BDAT message and hands back
+    /// its result, or ends the connection if it takes too long or the
+    /// session was lost.
+    async fn finish_transfer(&mut self, last: bool, server: &Server<B>) -> Option<DataResult<B::S>> {
+        let mut join_handle = self.data_result.take()?;
+        match with_timeout(server.data_termination_timeout, &mut join_handle).await {
+            Ok(Ok(res)) => Some(res),
+            Ok(Err(err)) => {
+                // The session went down with the task, so the connection
+                // can't go on.
+                conn_log!(self.log(), Level::Error; "message transfer task failed: {}", err);
+                self.bdat_pipe = None;
+                self.reject_chunk(last, 451, [4, 3, 0], "Internal server error", server).await;
+                let _ = self.close().await;
+                None
+            }
+            Err(_) => {
+                join_handle.abort();
+                self.timed_out("data termination").await;
+                None
+            }
+        }
+    }
+
+    /// Ends the connection after a BDAT chunk couldn't be read.
+    async fn chunk_failed(&mut self, err: io::Error) {
+        if err.kind() == io::ErrorKind::TimedOut {
+            self.timed_out("data block").await;
+            return;
+        }
+        conn_log!(self.log(), Level::Warn; "error reading BDAT chunk: {}", err);
+        let _ = self.close().await;
+    }
+
+    pub async fn reset(&mut self) {
+        self.abort_transfer().await;
         self.bytes_received = 0;
 
         if let Some(session) = self.session.as_mut() {
@@ -849,43 +1389,131 @@ impl<B: Backend> Conn<B> {
         }
 
         self.from_received = false;
+        self.utf8 = false;
+        self.binarymime = false;
         self.recipients = Vec::new();
+        self.tracker.set_state(TransactionState::Idle);
     }
 }
 
-fn decode_xtext(val: String) -> Result<String> {
-    if !val.contains('+') {
-        return Ok(val);
+/// Discards the rest of a DATA message, too long lines included, and returns
+/// how many bytes that was. Stops once that is more than `MAX_DRAIN_BYTES`.
+async fn drain_data<R: AsyncBufRead + Unpin>(r: &mut TimedReader<DataReader<'_, LineLimitReader<R>>>) -> io::Result<u64> {
+    let mut buf = vec![0; 8 * 1024];
+    let mut read = 0;
+    loop {
+        // Skipped lines count towards the cap too, and may be endless.
+        let lr = r.get_mut().get_mut();
+        lr.skip_long_lines = true;
+        lr.skip_limit = MAX_DRAIN_BYTES - read;
+
+        let n = match r.read(&mut buf).await {
+            Ok(n) => n as u64,
+            Err(_) if r.get_mut().get_mut().skipped > r.get_mut().get_mut().skip_limit => return Ok(MAX_DRAIN_BYTES + 1),
+            Err(err) => return Err(err),
+        };
+        read += n;
+        let drained = read + r.get_mut().get_mut().skipped;
+        if n == 0 || drained > MAX_DRAIN_BYTES {
+            return Ok(drained);
+        }
     }
+}
 
-    let hex_char_re = Regex::new(r"\+[0-9A-F]?[0-9A-F]?").unwrap();
-
-    let mut replace_err = None;
+/// The reply for a backend error: the one given by the `SMTPError` it
+/// carries, or `code` and `ec` with the error's message. An `SMTPError`
+/// without an error code gets `code` and `ec` too, so that a failure is
+/// never replied to as a success.
+fn error_status(err: &anyhow::Error, code: u16, ec: EnhancedCode) -> (u16, EnhancedCode, String) {
+    match err.downcast_ref::<SMTPError>() {
+        Some(err) if (400..=599).contains(&err.code) => (err.code, err.enhanced_code, err.message.clone()),
+        Some(err) => (code, ec, err.message.clone()),
+        None => (code, ec, err.to_string()),
+    }
+}
 
-    let mut decoded = val.clone();
+fn data_status(res: &Result<()>) -> (u16, EnhancedCode, String) {
+    match res {
+        Ok(()) => (250, [2, 0, 0], "OK".to_string()),
+        Err(err) => error_status(err, 554, [5, 0, 0]),
+    }
+}
 
-    for re_match in hex_char_re.find_iter(&val) {
-        let str_re_match = re_match.as_str();
-        if str_re_match.len() != 3 {
-            replace_err = Some(anyhow!("incomplete hexchar"));
-            decoded.replace_range(re_match.range(), "");
-        }
-        let char = u8::from_str_radix(str_re_match, 16);
-        if char.is_err() {
-            replace_err = Some(anyhow!("invalid hexchar"));
-            decoded.replace_range(re_match.range(), "");
+/// Decodes an RFC 3461 xtext, where any character but printable ASCII, `+`
+/// and `=` is encoded as `+` followed by two uppercase hex digits.
+fn decode_xtext(val: &str) -> Result<String> {
+    let mut decoded = Vec::with_capacity(val.len());
+    let mut bytes = val.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => {
+                let hex = [bytes.next(), bytes.next()];
+                match hex {
+                    [Some(hi), Some(lo)] if is_xtext_hex(hi) && is_xtext_hex(lo) => {
+                        decoded.push(hex_value(hi) << 4 | hex_value(lo));
+                    }
+                    _ => bail!("invalid hexchar in xtext"),
+                }
+            }
+            b'!'..=b'~' if b != b'=' => decoded.push(b),
+            _ => bail!("invalid character in xtext"),
         }
-        decoded.replace_range(
-            re_match.range(),
-            &String::from_utf8(vec![char.unwrap()]).unwrap(),
-        );
     }
 
-    if replace_err.is_some() {
-        return Err(replace_err.unwrap());
+    String::from_utf8(decoded).map_err(|_| anyhow!("xtext is not valid UTF-8"))
+}
+
+fn is_xtext_hex(b: u8) -> bool {
+    b.is_ascii_digit() || (b'A'..=b'F').contains(&b)
+}
+
+fn hex_value(b: u8) -> u8 {
+    match b {
+        b'0'..=b'9' => b - b'0',
+        _ => b - b'A' + 10,
     }
+}
+
+/// Parses a DSN `NOTIFY` value, either `NEVER` or a list of `SUCCESS`,
+/// `FAILURE` and `DELAY`.
+fn parse_notify(val: &str) -> Result<Vec<DSNNotify>> {
+    let mut notify = Vec::new();
+    for keyword in val.split(',') {
+        let n = match keyword.to_uppercase().as_str() {
+            "NEVER" => DSNNotify::Never,
+            "SUCCESS" => DSNNotify::Success,
+            "FAILURE" => DSNNotify::Failure,
+            "DELAY" => DSNNotify::Delay,
+            _ => bail!("unknown NOTIFY keyword {}", keyword),
+        };
+        if notify.contains(&n) {
+            bail!("duplicate NOTIFY keyword {}", keyword);
+        }
+        notify.push(n);
+    }
+    if notify.len() > 1 && notify.contains(&DSNNotify::Never) {
+        bail!("NOTIFY=NEVER can't be combined with other keywords");
+    }
+    Ok(notify)
+}
 
-    Ok(decoded)
+/// Parses a DSN `ORCPT` value, `addr-type;xtext`.
+fn parse_orcpt(val: &str) -> Result<OriginalRecipient> {
+    if val.len() > MAX_ORCPT_LEN {
+        bail!("ORCPT too long");
+    }
+    let (addr_type, addr) = val.split_once(';').ok_or_else(|| anyhow!("missing address type"))?;
+    if addr_type.is_empty() || !addr_type.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        bail!("invalid address type");
+    }
+    let addr = decode_xtext(addr)?;
+    if addr.is_empty() {
+        bail!("empty original recipient");
+    }
+    Ok(OriginalRecipient {
+        addr_type: addr_type.to_string(),
+        addr,
+    })
 }
 
 fn _encode_xtext(raw: String) -> String {
@@ -905,16 +1533,669 @@ fn _encode_xtext(raw: String) -> String {
     out
 }
 
-/*
-func toSMTPStatus(err error) (code int, enchCode EnhancedCode, msg string) {
-    if err != nil {
-        if smtperr, ok := err.(*SMTPError); ok {
-            return smtperr.Code, smtperr.EnhancedCode, smtperr.Message
-        } else {
-            return 554, EnhancedCode{5, 0, 0}, "Error: transaction failed, blame it on the weather: " + err.Error()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use crate::address::Address;
+    use crate::shutdown::ShutdownHandle;
+
+    type Messages = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    #[derive(Default)]
+    struct TestBackend {
+        messages: Messages,
+        // How much of each message the session reads, all of it if `None`.
+        read_limit: Option<usize>,
+        // How long the session takes with each message once it read it.
+        delay: Duration,
+        logouts: Arc<AtomicUsize>,
+    }
+
+    struct TestSession {
+        messages: Messages,
+        read_limit: Option<usize>,
+        delay: Duration,
+        logouts: Arc<AtomicUsize>,
+    }
+
+    impl Backend for TestBackend {
+        type S = TestSession;
+
+        fn new_session<T: Transport>(&self, _c: &mut Conn<Self, T>) -> Result<TestSession> {
+            Ok(TestSession {
+                messages: self.messages.clone(),
+                read_limit: self.read_limit,
+                delay: self.delay,
+                logouts: self.logouts.clone(),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl Session for TestSession {
+        async fn mail(&mut self, _: &Address, _: &MailOptions) -> Result<()> {
+            Ok(())
+        }
+
+        async fn rcpt(&mut self, _: &Address, _: &RcptOptions) -> Result<()> {
+            Ok(())
+        }
+
+        async fn data<R: AsyncRead + Send + Unpin>(&mut self, mut r: R) -> Result<()> {
+            let limit = self.read_limit.map_or(u64::MAX, |limit| limit as u64);
+            let mut msg = Vec::new();
+            (&mut r).take(limit).read_to_end(&mut msg).await?;
+            tokio::time::sleep(self.delay).await;
+            self.messages.lock().unwrap().push(msg);
+            Ok(())
+        }
+
+        /// Fails the delivery to recipients at `full.example.com`.
+        async fn lmtp_data<R: AsyncRead + Send + Unpin>(&mut self, r: R, status: &mut StatusCollector) -> Result<()> {
+            let res = self.data(r).await;
+            let full: Vec<String> = status
+                .recipients()
+                .into_iter()
+                .filter(|rcpt| rcpt.ends_with("@full.example.com"))
+                .map(str::to_string)
+                .collect();
+            for rcpt in full {
+                status.set_status(&rcpt, Err(SMTPError::new(452, [4, 2, 2], "Mailbox full").into()));
+            }
+            res
+        }
+
+        fn authenticators(&mut self) -> Vec<Box<dyn sasl::Server>> {
+            vec![Box::new(sasl::AnonymousServer::new(TestAuthenticator))]
+        }
+
+        fn reset(&mut self) {}
+
+        fn logout(&mut self) -> Result<()> {
+            self.logouts.fetch_add(1, Ordering::Relaxed);
+            Ok(())
         }
     }
 
-    return 250, EnhancedCode{2, 0, 0}, "OK: queued"
+    fn test_server(backend: TestBackend) -> Server<TestBackend> {
+        let mut server = Server::new(backend);
+        server.domain = "localhost".to_string();
+        server
+    }
+
+    /// Sends `script` to a session of `server` and returns the last line of
+    /// each reply.
+    async fn run_session(server: &Server<TestBackend>, script: &[u8]) -> Vec<String> {
+        run_session_with(server, script, 8 * 1024, true).await
+    }
+
+    /// Like `run_session`, with a read buffer of `capacity` bytes on the
+    /// connection. Unless `hang_up` is set, the client stays connected after
+    /// the script until the server closes the connection.
+    async fn run_session_with(server: &Server<TestBackend>, script: &[u8], capacity: usize, hang_up: bool) -> Vec<String> {
+        let (client, stream) = io::duplex(64 * 1024);
+        let (mut rx, mut tx) = io::split(client);
+
+        let mut c = Conn::new(stream, server.max_line_length);
+        c.stream = BufReader::with_capacity(capacity, c.stream.into_inner());
+        let conn = server.handle_conn(c);
+        // The server may close the connection before the end of the script.
+        let write = async {
+            let _ = tx.write_all(script).await;
+            if hang_up {
+                let _ = tx.shutdown().await;
+            }
+        };
+        let read = async {
+            let mut replies = String::new();
+            rx.read_to_string(&mut replies).await.unwrap();
+            replies
+        };
+        let (_, _, replies) = tokio::join!(conn, write, read);
+
+        replies
+            .lines()
+            .filter(|line| line.as_bytes().get(3) == Some(&b' '))
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn codes(replies: &[String]) -> Vec<&str> {
+        replies.iter().map(|reply| &reply[..3]).collect()
+    }
+
+    #[tokio::test]
+    async fn rejected_bdat_chunk_fails_the_transfer() {
+        let backend = TestBackend::default();
+        let messages = backend.messages.clone();
+        let server = test_server(backend);
+
+        let script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\n\
+            BDAT 5\r\nhelloBDAT 5 X\r\nworldBDAT 3 LAST\r\nend\
+            MAIL FROM:<a@example.com>\r\nQUIT\r\n";
+        let replies = run_session(&server, script).await;
+        assert_eq!(codes(&replies), ["220", "250", "250", "250", "250", "501", "502", "250", "221"], "{:?}", replies);
+        assert!(messages.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn error_status_keeps_error_codes_only() {
+        let err = SMTPError::new(550, [5, 1, 1], "No such user").into();
+        assert_eq!(error_status(&err, 451, [4, 0, 0]), (550, [5, 1, 1], "No such user".to_string()));
+
+        let err = SMTPError::new(250, [2, 0, 0], "Fine").into();
+        assert_eq!(error_status(&err, 451, [4, 0, 0]), (451, [4, 0, 0], "Fine".to_string()));
+
+        let err = anyhow!("backend down");
+        assert_eq!(error_status(&err, 451, [4, 0, 0]), (451, [4, 0, 0], "backend down".to_string()));
+    }
+
+    #[test]
+    fn decodes_xtext() {
+        assert_eq!(decode_xtext("abc").unwrap(), "abc");
+        assert_eq!(decode_xtext("a+2Bb+3Dc").unwrap(), "a+b=c");
+        assert_eq!(decode_xtext("+C3+A9").unwrap(), "é");
+        assert_eq!(decode_xtext("").unwrap(), "");
+    }
+
+    #[test]
+    fn rejects_invalid_xtext() {
+        for val in ["+", "+2", "a+", "+2b", "+GG", "a=b", "a b", "é", "+FF"] {
+            assert!(decode_xtext(val).is_err(), "{:?}", val);
+        }
+    }
+
+    #[test]
+    fn parses_notify() {
+        assert_eq!(parse_notify("NEVER").unwrap(), vec![DSNNotify::Never]);
+        assert_eq!(
+            parse_notify("success,Delay").unwrap(),
+            vec![DSNNotify::Success, DSNNotify::Delay]
+        );
+        for val in ["", "NEVER,SUCCESS", "SUCCESS,SUCCESS", "SOMETIMES", "SUCCESS,", "é"] {
+            assert!(parse_notify(val).is_err(), "{:?}", val);
+        }
+    }
+
+    #[test]
+    fn parses_orcpt() {
+        let orcpt = parse_orcpt("rfc822;a+2Bb@example.com").unwrap();
+        assert_eq!(orcpt.addr_type, "rfc822");
+        assert_eq!(orcpt.addr, "a+b@example.com");
+
+        let long = format!("rfc822;{}", "a".repeat(MAX_ORCPT_LEN));
+        for val in ["rfc822", ";a@b", "rfc 822;a@b", "rfc822;", "rfc822;a+4", "é;a@b", long.as_str()] {
+            assert!(parse_orcpt(val).is_err(), "{:?}", val);
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_bdat_transfer_logs_the_session_out() {
+        let backend = TestBackend::default();
+        let logouts = backend.logouts.clone();
+        let messages = backend.messages.clone();
+        let server = test_server(backend);
+
+        let script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nBDAT 5\r\nhello";
+        let replies = run_session(&server, script).await;
+        assert_eq!(codes(&replies), ["220", "250", "250", "250", "250"], "{:?}", replies);
+        assert_eq!(logouts.load(Ordering::Relaxed), 1);
+        assert!(messages.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lmtp_replies_per_recipient() {
+        let mut server = test_server(TestBackend::default());
+        server.lmtp = true;
+
+        let script = b"LHLO client\r\n\
+            MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nRCPT TO:<c@full.example.com>\r\n\
+            DATA\r\nhello\r\n.\r\n\
+            MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nRCPT TO:<c@full.example.com>\r\n\
+            BDAT 7 LAST\r\nhello\r\nQUIT\r\n";
+        let replies = run_session(&server, script).await;
+        assert_eq!(
+            codes(&replies),
+            ["220", "250", "250", "250", "250", "354", "250", "452", "250", "250", "250", "250", "452", "221"],
+            "{:?}",
+            replies
+        );
+    }
+
+    #[tokio::test]
+    async fn too_many_errors_close_the_connection() {
+        let mut server = test_server(TestBackend::default());
+        server.max_errors = 3;
+
+        let script = b"EHLO client\r\nFOO\r\nBAR\r\nBAZ\r\nNOOP\r\n";
+        let replies = run_session(&server, script).await;
+        assert_eq!(codes(&replies), ["220", "250", "501", "501", "421"], "{:?}", replies);
+        assert_eq!(replies[4], "421 4.7.0 Too many errors");
+    }
+
+    #[tokio::test]
+    async fn overlong_data_line_is_rejected_in_sync() {
+        let backend = TestBackend::default();
+        let messages = backend.messages.clone();
+        let mut server = test_server(backend);
+        server.max_line_length = 100;
+
+        let mut script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nshort\r\n".to_vec();
+        script.extend_from_slice(&[b'x'; 300]);
+        script.extend_from_slice(b"\r\nend\r\n.\r\nNOOP\r\nQUIT\r\n");
+        let replies = run_session(&server, &script).await;
+        assert_eq!(codes(&replies), ["220", "250", "250", "250", "354", "554", "250", "221"], "{:?}", replies);
+        assert_eq!(replies[5], "554 5.6.0 Line too long");
+        // The session's read failed at the long line.
+        assert!(messages.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn overlong_last_data_line_still_ends_the_message() {
+        let mut server = test_server(TestBackend::default());
+        server.max_line_length = 100;
+
+        for capacity in [1, 99, 100, 101, 300, 8192] {
+            let mut script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nhello\r\n".to_vec();
+            script.extend_from_slice(&vec![b'x'; 300]);
+            script.extend_from_slice(b"\r\n.\r\nQUIT\r\n");
+            let replies = run_session_with(&server, &script, capacity, true).await;
+            assert_eq!(codes(&replies), ["220", "250", "250", "250", "354", "554", "221"], "capacity {}: {:?}", capacity, replies);
+        }
+    }
+
+    #[tokio::test]
+    async fn requiretls_is_checked() {
+        let mut server = test_server(TestBackend::default());
+        server.enable_requiretls = true;
+
+        let script = b"EHLO client\r\nMAIL FROM:<a@example.com> REQUIRETLS\r\n\
+            MAIL FROM:<a@example.com> REQUIRETLS=x\r\nQUIT\r\n";
+        let replies = run_session(&server, script).await;
+        assert_eq!(
+            replies[2..],
+            ["530 5.7.10 REQUIRETLS requires a TLS connection", "501 5.5.4 REQUIRETLS takes no value", "221 2.0.0 Bye"],
+            "{:?}",
+            replies
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_parameters_are_rejected() {
+        let server = test_server(TestBackend::default());
+
+        let script = b"EHLO client\r\nMAIL FROM:<a@example.com> FOO=bar\r\nMAIL FROM:<a@example.com> RET=FULL\r\n\
+            MAIL FROM:<a@example.com> ENVID=x\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com> FOO=bar\r\n\
+            RCPT TO:<b@example.com> NOTIFY=NEVER\r\nRCPT TO:<b@example.com> ORCPT=rfc822;b@example.com\r\n\
+            RCPT TO:<b@example.com>\r\nQUIT\r\n";
+        let replies = run_session(&server, script).await;
+        assert_eq!(
+            codes(&replies),
+            ["220", "250", "555", "555", "555", "250", "555", "555", "555", "250", "221"],
+            "{:?}",
+            replies
+        );
+    }
+
+    #[tokio::test]
+    async fn data_is_refused_for_binarymime() {
+        let mut server = test_server(TestBackend::default());
+        server.enable_binarymime = true;
+
+        let script = b"EHLO client\r\nMAIL FROM:<a@example.com> BODY=BINARYMIME\r\nRCPT TO:<b@example.com>\r\n\
+            DATA\r\nBDAT 5 LAST\r\nhelloQUIT\r\n";
+        let replies = run_session(&server, script).await;
+        assert_eq!(codes(&replies), ["220", "250", "250", "250", "503", "250", "221"], "{:?}", replies);
+    }
+
+    #[tokio::test]
+    async fn unread_data_is_drained_before_the_reply() {
+        let backend = TestBackend {
+            read_limit: Some(5),
+            ..Default::default()
+        };
+        let messages = backend.messages.clone();
+        let server = test_server(backend);
+
+        let script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\n\
+            DATA\r\nhello\r\nRSET\r\nNOOP\r\n.\r\nQUIT\r\n";
+        let replies = run_session(&server, script).await;
+        assert_eq!(codes(&replies), ["220", "250", "250", "250", "354", "250", "221"], "{:?}", replies);
+        assert_eq!(*messages.lock().unwrap(), [b"hello".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn endless_unread_data_line_closes_the_connection() {
+        let backend = TestBackend {
+            read_limit: Some(5),
+            ..Default::default()
+        };
+        let server = test_server(backend);
+
+        let mut script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nhello\r\n".to_vec();
+        script.resize(script.len() + MAX_DRAIN_BYTES as usize + 1024, b'x');
+        let replies = run_session(&server, &script).await;
+        assert_eq!(codes(&replies), ["220", "250", "250", "250", "354", "421"], "{:?}", replies);
+        assert_eq!(replies[5], "421 4.3.0 Too much unread data, closing connection");
+    }
+
+    #[tokio::test]
+    async fn too_much_unread_data_closes_the_connection() {
+        let backend = TestBackend {
+            read_limit: Some(5),
+            ..Default::default()
+        };
+        let server = test_server(backend);
+
+        let mut line = vec![b'x'; 1022];
+        line.extend_from_slice(b"\r\n");
+        let mut script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n".to_vec();
+        for _ in 0..=MAX_DRAIN_BYTES / line.len() as u64 {
+            script.extend_from_slice(&line);
+        }
+        script.extend_from_slice(b".\r\nQUIT\r\n");
+        let replies = run_session(&server, &script).await;
+        assert_eq!(codes(&replies), ["220", "250", "250", "250", "354", "421"], "{:?}", replies);
+        assert_eq!(replies[5], "421 4.3.0 Too much unread data, closing connection");
+    }
+
+    /// Reads the last line of the next reply, or an empty string once the
+    /// server closed the connection.
+    async fn read_reply<R: AsyncBufRead + Unpin>(rx: &mut R) -> String {
+        loop {
+            let mut line = String::new();
+            if rx.read_line(&mut line).await.unwrap() == 0 || line.as_bytes().get(3) == Some(&b' ') {
+                return line.trim_end().to_string();
+            }
+        }
+    }
+
+    async fn command<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(rx: &mut R, tx: &mut W, cmd: &str) -> String {
+        tx.write_all(format!("{}\r\n", cmd).as_bytes()).await.unwrap();
+        read_reply(rx).await
+    }
+
+    /// Serves `server` on a loopback port, and connects a client to it that
+    /// starts a transaction.
+    async fn serve_transaction(
+        server: Server<TestBackend>,
+    ) -> (ShutdownHandle, JoinHandle<Result<()>>, BufReader<OwnedReadHalf>, OwnedWriteHalf) {
+        let shutdown = server.shutdown_handle();
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        let serve = tokio::spawn(Arc::new(server).serve(l));
+
+        let (rx, mut tx) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut rx = BufReader::new(rx);
+        assert!(read_reply(&mut rx).await.starts_with("220 "));
+        assert!(command(&mut rx, &mut tx, "EHLO client").await.starts_with("250 "));
+        assert!(command(&mut rx, &mut tx, "MAIL FROM:<a@example.com>").await.starts_with("250 "));
+        assert!(command(&mut rx, &mut tx, "RCPT TO:<b@example.com>").await.starts_with("250 "));
+        (shutdown, serve, rx, tx)
+    }
+
+    async fn assert_serve_returns(serve: JoinHandle<Result<()>>) {
+        tokio::time::timeout(Duration::from_secs(10), serve).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_idle_sessions() {
+        let (shutdown, serve, mut rx, _tx) = serve_transaction(test_server(TestBackend::default())).await;
+
+        shutdown.shutdown();
+        assert_eq!(read_reply(&mut rx).await, "421 4.3.2 Service shutting down");
+        assert_eq!(read_reply(&mut rx).await, "");
+        assert_serve_returns(serve).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_data_finish() {
+        let backend = TestBackend::default();
+        let messages = backend.messages.clone();
+        let (shutdown, serve, mut rx, mut tx) = serve_transaction(test_server(backend)).await;
+
+        assert!(command(&mut rx, &mut tx, "DATA").await.starts_with("354 "));
+        tx.write_all(b"hello\r\n").await.unwrap();
+        shutdown.shutdown();
+        assert_eq!(command(&mut rx, &mut tx, ".").await, "250 2.0.0 OK");
+        assert_eq!(read_reply(&mut rx).await, "421 4.3.2 Service shutting down");
+        assert_serve_returns(serve).await;
+        assert_eq!(*messages.lock().unwrap(), [b"hello\r\n".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_bdat_finish() {
+        let backend = TestBackend::default();
+        let messages = backend.messages.clone();
+        let (shutdown, serve, mut rx, mut tx) = serve_transaction(test_server(backend)).await;
+
+        tx.write_all(b"BDAT 5\r\nhello").await.unwrap();
+        assert_eq!(read_reply(&mut rx).await, "250 2.0.0 Continue");
+        shutdown.shutdown();
+        tx.write_all(b"BDAT 3 LAST\r\nend").await.unwrap();
+        assert_eq!(read_reply(&mut rx).await, "250 2.0.0 OK");
+        assert_eq!(read_reply(&mut rx).await, "421 4.3.2 Service shutting down");
+        assert_serve_returns(serve).await;
+        assert_eq!(*messages.lock().unwrap(), [b"helloend".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_timeout_closes_and_logs_out() {
+        let backend = TestBackend::default();
+        let logouts = backend.logouts.clone();
+        let mut server = test_server(backend);
+        server.shutdown_timeout = Duration::from_millis(100);
+        let (shutdown, serve, mut rx, mut tx) = serve_transaction(server).await;
+
+        assert!(command(&mut rx, &mut tx, "DATA").await.starts_with("354 "));
+        shutdown.shutdown();
+        assert_eq!(read_reply(&mut rx).await, "421 4.3.2 Service shutting down");
+        assert_eq!(read_reply(&mut rx).await, "");
+        assert_serve_returns(serve).await;
+        assert_eq!(logouts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn registry_disconnects_sessions() {
+        let server = test_server(TestBackend::default());
+        let registry = server.registry();
+
+        let (client, stream) = io::duplex(8 * 1024);
+        let (rx, mut tx) = io::split(client);
+        let mut rx = BufReader::new(rx);
+        let client = async {
+            assert!(read_reply(&mut rx).await.starts_with("220 "));
+            assert!(command(&mut rx, &mut tx, "EHLO client").await.starts_with("250 "));
+            assert!(command(&mut rx, &mut tx, "MAIL FROM:<a@example.com>").await.starts_with("250 "));
+
+            let conns = registry.list();
+            assert_eq!(conns.len(), 1);
+            let id = conns[0].id;
+            let info = registry.get(id).unwrap();
+            assert_eq!(info.helo, "client");
+            assert_eq!(info.state, TransactionState::Mail);
+            assert!(!info.tls);
+
+            assert!(registry.disconnect(id));
+            assert_eq!(read_reply(&mut rx).await, "421 4.7.0 Connection closed by administrator");
+            assert_eq!(read_reply(&mut rx).await, "");
+            id
+        };
+        let (res, id) = tokio::join!(server.handle_conn(Conn::new(stream, server.max_line_length)), client);
+        assert!(res.is_ok());
+
+        assert!(registry.list().is_empty());
+        assert!(registry.get(id).is_none());
+        assert!(!registry.disconnect(id));
+    }
+
+    struct TestAuthenticator;
+
+    #[async_trait]
+    impl sasl::AnonymousAuthenticator for TestAuthenticator {
+        async fn authenticate(&mut self, _: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn implicit_tls_advertises_tls_only_extensions() {
+        let cert = Certificate(include_bytes!("../testdata/localhost.der").to_vec());
+        let key = PrivateKey(include_bytes!("../testdata/localhost.key.der").to_vec());
+        let tls_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(include_bytes!("../testdata/ca.der").to_vec())).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+
+        let mut server = test_server(TestBackend::default());
+        server.tls_acceptor = Some(TlsAcceptor::from(Arc::new(tls_config)));
+        server.allow_insecure_auth = false;
+        server.enable_requiretls = true;
+
+        let (client, stream) = io::duplex(16 * 1024);
+        let serve = async {
+            let stream = server.tls_acceptor.as_ref().unwrap().accept(stream).await.unwrap();
+            server.handle_conn(Conn::new_tls(stream, server.max_line_length)).await
+        };
+        let client = async {
+            let client = connector.connect("localhost".try_into().unwrap(), client).await.unwrap();
+            let (rx, mut tx) = io::split(client);
+            let mut rx = BufReader::new(rx);
+            assert!(read_reply(&mut rx).await.starts_with("220 "));
+
+            tx.write_all(b"EHLO client\r\n").await.unwrap();
+            let mut caps = Vec::new();
+            loop {
+                let mut line = String::new();
+                rx.read_line(&mut line).await.unwrap();
+                caps.push(line[4..].trim_end().to_string());
+                if line.as_bytes()[3] == b' ' {
+                    break;
+                }
+            }
+            assert_eq!(command(&mut rx, &mut tx, "QUIT").await, "221 2.0.0 Bye");
+            caps
+        };
+        let (res, caps) = tokio::join!(serve, client);
+        assert!(res.is_ok());
+
+        assert!(caps.iter().any(|cap| cap == "AUTH ANONYMOUS"), "{:?}", caps);
+        assert!(caps.iter().any(|cap| cap == "REQUIRETLS"), "{:?}", caps);
+        assert!(!caps.iter().any(|cap| cap == "STARTTLS"), "{:?}", caps);
+    }
+
+    /// Sends `script` to a session of `server` and waits without hanging up.
+    /// Returns the last reply, and how long the session lasted.
+    async fn run_stalled_session(server: &Server<TestBackend>, script: &[u8]) -> (String, Duration) {
+        let start = tokio::time::Instant::now();
+        let replies = run_session_with(server, script, 8 * 1024, false).await;
+        (replies.last().unwrap().clone(), start.elapsed())
+    }
+
+    const TIMEOUT_REPLY: &str = "421 4.4.2 Timeout exceeded, closing connection";
+
+    #[tokio::test(start_paused = true)]
+    async fn greeting_timeout() {
+        let mut server = test_server(TestBackend::default());
+        server.greeting_timeout = Duration::from_secs(10);
+
+        let (reply, elapsed) = run_stalled_session(&server, b"").await;
+        assert_eq!(reply, TIMEOUT_REPLY);
+        assert_eq!(elapsed, server.greeting_timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn command_timeout() {
+        let server = test_server(TestBackend::default());
+
+        let (reply, elapsed) = run_stalled_session(&server, b"EHLO client\r\n").await;
+        assert_eq!(reply, TIMEOUT_REPLY);
+        assert_eq!(elapsed, server.read_timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn data_block_timeout() {
+        let server = test_server(TestBackend::default());
+
+        let script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nhello\r\n";
+        let (reply, elapsed) = run_stalled_session(&server, script).await;
+        assert_eq!(reply, TIMEOUT_REPLY);
+        assert_eq!(elapsed, server.data_block_timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bdat_block_timeout() {
+        let server = test_server(TestBackend::default());
+
+        let script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nBDAT 10 LAST\r\nhello";
+        let (reply, elapsed) = run_stalled_session(&server, script).await;
+        assert_eq!(reply, TIMEOUT_REPLY);
+        assert_eq!(elapsed, server.data_block_timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn data_termination_timeout() {
+        let backend = TestBackend {
+            delay: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let mut server = test_server(backend);
+        server.data_termination_timeout = Duration::from_secs(60);
+
+        let script = b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\nhello\r\n.\r\n";
+        let (reply, elapsed) = run_stalled_session(&server, script).await;
+        assert_eq!(reply, TIMEOUT_REPLY);
+        assert_eq!(elapsed, server.data_termination_timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_timeout() {
+        let mut server = test_server(TestBackend::default());
+        server.session_timeout = Duration::from_secs(60);
+
+        let (reply, elapsed) = run_stalled_session(&server, b"EHLO client\r\n").await;
+        assert_eq!(reply, TIMEOUT_REPLY);
+        assert_eq!(elapsed, server.session_timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn write_timeout() {
+        let mut server = test_server(TestBackend::default());
+        server.write_timeout = Duration::from_secs(10);
+
+        // The client sends commands but never reads the replies, so no reply
+        // can get through once the buffers are full, not even a 421.
+        let (client, stream) = io::duplex(256);
+        let (_rx, mut tx) = io::split(client);
+        let start = tokio::time::Instant::now();
+        let write = async {
+            for _ in 0..1000 {
+                if tx.write_all(b"NOOP\r\n").await.is_err() {
+                    break;
+                }
+            }
+        };
+        let (res, _) = tokio::join!(server.handle_conn(Conn::new(stream, server.max_line_length)), write);
+        assert!(res.is_ok());
+        assert_eq!(start.elapsed(), server.write_timeout);
+    }
 }
- */
@@ -1,13 +1,20 @@
-use std::{pin::Pin, future::Future, task::Poll, io::{ErrorKind, Error}};
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use tokio::io::{self, AsyncRead, AsyncBufReadExt, AsyncBufRead, BufReader};
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
 
 pub type EnhancedCode = [i8; 3];
 
+/// An SMTP reply to send for a failed command. Return it, wrapped in an
+/// `anyhow::Error`, from the `Session` callbacks to choose the reply the
+/// client gets; other errors, and codes outside 400-599, are sent with a
+/// generic code.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SMTPError {
-    code: u16,
-    enhanced_code: EnhancedCode,
-    message: String,
+    pub code: u16,
+    pub enhanced_code: EnhancedCode,
+    pub message: String,
 }
 
 impl std::fmt::Display for SMTPError {
@@ -16,170 +23,358 @@ impl std::fmt::Display for SMTPError {
     }
 }
 
+impl std::error::Error for SMTPError {}
+
 pub const NO_ENHANCED_CODE: EnhancedCode = [-1, -1, -1];
 
 pub const ENHANCED_CODE_NOT_SET: EnhancedCode = [0, 0, 0];
 
-#[derive(PartialEq)]
+// Only CRLF ends a line: a bare LF is message data, so that `\n.\n` and
+// similar can't end the message early (SMTP smuggling).
+#[derive(Clone, Copy, PartialEq)]
 enum State {
     BeginLine,
+    // A dot at the start of a line, removed as dot-stuffing or the start of
+    // the terminating line.
     Dot,
     DotCR,
-    CR,
     Data,
-    EOF,
+    // A CR in line content, which ends the line if a LF follows.
+    CR,
+    Eof,
 }
 
 
 impl SMTPError {
+    /// Creates an error replied as `code`, e.g.
+    /// `SMTPError::new(550, [5, 1, 1], "No such user")`. Use
+    /// `ENHANCED_CODE_NOT_SET` for an enhanced code derived from `code`, or
+    /// `NO_ENHANCED_CODE` to send none.
+    pub fn new(code: u16, enhanced_code: EnhancedCode, message: impl Into<String>) -> Self {
+        SMTPError {
+            code,
+            enhanced_code,
+            message: message.into(),
+        }
+    }
+
     pub fn err_data_too_large() -> Self {
-        return SMTPError {
+        SMTPError {
             code: 552,
             enhanced_code: ENHANCED_CODE_NOT_SET,
             message: "Requested mail action aborted: exceeded storage allocation".to_string(),
-        };
+        }
     }
 
     pub fn err_auth_required() -> Self {
-        return SMTPError {
+        SMTPError {
             code: 502,
             enhanced_code: [5, 7, 0],
             message: "Please authenticate first".to_string(),
-        };
+        }
     }
 
     pub fn err_auth_unsupported() -> Self {
-        return SMTPError {
+        SMTPError {
             code: 502,
             enhanced_code: [5, 7, 0],
             message: "Authentication not supported".to_string(),
-        };
+        }
     }
 
     pub fn error(&self) -> String {
         self.message.clone()
     }
 
-    fn is_temporary(&self) -> bool {
+    pub fn is_temporary(&self) -> bool {
         self.code >= 400 && self.code < 500
     }
 }
 
 const ERR_DATA_TOO_LARGE: &str = "Data too large";
 
-pub struct DataReader<'a, R: AsyncRead + Unpin> {
-    pub r: BufReader<&'a mut R>,
-    state: State,
-    pub limited: bool,
-    n: usize,
+/// Reads a message sent with DATA: removes the dot-stuffing and ends at the
+/// `.` line, which is consumed but not returned. Reads never go past the
+/// terminating line, so the commands that follow it are left in `r`.
+pub struct DataReader<'a, R: AsyncBufRead + Unpin> {
+    r: &'a mut R,
+    scanner: Scanner,
 }
 
 impl<'a, R: AsyncBufRead + Unpin> DataReader<'a, R> {
     pub fn new(r: &'a mut R, max_message_bytes: usize) -> Self {
         DataReader {
-            r: BufReader::new(r),
-            state: State::BeginLine,
-            limited: max_message_bytes > 0,
-            n: max_message_bytes,
+            r,
+            scanner: Scanner {
+                state: State::BeginLine,
+                limited: max_message_bytes > 0,
+                n: max_message_bytes,
+                count: 0,
+                too_large: false,
+            },
         }
     }
-}
 
-impl<'a, R: AsyncRead + Unpin> AsyncRead for DataReader<'a, R> {
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        let this = self.as_mut().get_mut();
+    /// The number of message bytes read so far.
+    pub fn count(&self) -> usize {
+        self.scanner.count
+    }
 
-        if this.state == State::EOF {
-            return std::task::Poll::Ready(Ok(()));
-        }
+    /// Whether the message turned out to be larger than the size limit.
+    pub fn too_large(&self) -> bool {
+        self.scanner.too_large
+    }
 
-        if this.limited && this.n <= 0 {
-            return std::task::Poll::Ready(Err(Error::new(
-                ErrorKind::Other,
-                ERR_DATA_TOO_LARGE,
-            )));
-        }
+    /// Lets the rest of the message be read past the size limit.
+    pub fn remove_limit(&mut self) {
+        self.scanner.limited = false;
+    }
 
-        let bytes = match futures::ready!(Pin::new(&mut this.r).poll_fill_buf(cx)) {
-            Ok(bytes) => {
-                
-                if bytes.is_empty() {
-                    return std::task::Poll::Ready(Err(Error::new(
-                        ErrorKind::UnexpectedEof,
-                        "Unexpected EOF",
-                    )));
-                }
+    pub fn get_mut(&mut self) -> &mut R {
+        self.r
+    }
+}
 
-                if bytes.len() > this.n { &bytes[..this.n] } else { bytes }
-            }
-            Err(e) => return std::task::Poll::Ready(Err(e)),
-        };
-        
+struct Scanner {
+    state: State,
+    limited: bool,
+    // The bytes left before the size limit.
+    n: usize,
+    count: usize,
+    too_large: bool,
+}
 
+impl Scanner {
+    /// Processes as much of `input` as fits in `buf` and the size limit,
+    /// copying whole spans of line content at once. Returns the number of
+    /// input bytes consumed, and whether processing stopped because the
+    /// message exceeds the limit.
+    fn process(&mut self, input: &[u8], buf: &mut ReadBuf<'_>) -> (usize, bool) {
         let mut consumed = 0;
-        for c in bytes {
-            let c = *c;
-            consumed += 1;
-            match this.state {
+        while consumed < input.len() && buf.remaining() > 0 && self.state != State::Eof {
+            let rest = &input[consumed..];
+            match self.state {
                 State::BeginLine => {
-                    if c == b'.' {
-                        this.state = State::Dot;
-                        continue;
+                    if rest[0] == b'.' {
+                        self.state = State::Dot;
+                        consumed += 1;
+                    } else {
+                        self.state = State::Data;
                     }
-                    this.state = State::Data;
                 }
                 State::Dot => {
-                    if c == b'\r' {
-                        this.state = State::DotCR;
-                        continue;
-                    }
-                    if c == b'\n' {
-                        this.state = State::EOF;
-                        break;
+                    if rest[0] == b'\r' {
+                        self.state = State::DotCR;
+                        consumed += 1;
+                    } else {
+                        self.state = State::Data;
                     }
-
-                    this.state = State::Data;
                 }
                 State::DotCR => {
-                    if c == b'\n' {
-                        this.state = State::EOF;
-                        break;
-                    }
-                    this.state = State::Data;
-                }
-                State::CR => {
-                    if c == b'\n' {
-                        this.state = State::BeginLine;
-                        break;
+                    if rest[0] == b'\n' {
+                        self.state = State::Eof;
+                        consumed += 1;
+                    } else {
+                        // Not the terminating line after all: keep the CR.
+                        if self.emit(b"\r", buf).is_none() {
+                            return (consumed, true);
+                        }
+                        self.state = State::Data;
                     }
-                    this.state = State::Data;
                 }
                 State::Data => {
-                    if c == b'\r' {
-                        this.state = State::CR;
+                    let (span, ends_with_cr) = match rest.iter().position(|&c| c == b'\r') {
+                        Some(i) => (&rest[..=i], true),
+                        None => (rest, false),
+                    };
+                    let Some(n) = self.emit(span, buf) else {
+                        return (consumed, true);
+                    };
+                    consumed += n;
+                    if ends_with_cr && n == span.len() {
+                        self.state = State::CR;
                     }
-                    if c == b'\n' {
-                        this.state = State::BeginLine;
+                }
+                State::CR => {
+                    if rest[0] == b'\n' {
+                        if self.emit(b"\n", buf).is_none() {
+                            return (consumed, true);
+                        }
+                        consumed += 1;
+                        self.state = State::BeginLine;
+                    } else {
+                        self.state = State::Data;
                     }
                 }
-                _ => (),
+                State::Eof => unreachable!(),
             }
+        }
+        (consumed, false)
+    }
 
-            buf.put_slice(&[c]);
-            this.n -= 1;
+    /// Copies as much of `span` as fits into `buf`, returning how much was
+    /// copied, or `None` if the size limit leaves no room for any of it.
+    fn emit(&mut self, span: &[u8], buf: &mut ReadBuf<'_>) -> Option<usize> {
+        let mut n = span.len().min(buf.remaining());
+        if self.limited {
+            if self.n == 0 {
+                self.too_large = true;
+                return None;
+            }
+            n = n.min(self.n);
+            self.n -= n;
         }
+        buf.put_slice(&span[..n]);
+        self.count += n;
+        Some(n)
+    }
+}
 
-        this.r.consume(consumed);
+impl<'a, R: AsyncBufRead + Unpin> AsyncRead for DataReader<'a, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
 
-        if this.state != State::EOF {
-            cx.waker().wake_by_ref();
-            return Poll::Pending;
+        while this.scanner.state != State::Eof && buf.remaining() > 0 {
+            let input = match Pin::new(&mut *this.r).poll_fill_buf(cx) {
+                // Hand out what was read so far rather than waiting for more.
+                Poll::Pending if buf.filled().len() > start => break,
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(input)) => input,
+            };
+            if input.is_empty() {
+                if buf.filled().len() > start {
+                    break;
+                }
+                return Poll::Ready(Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected EOF")));
+            }
+
+            // What was scanned before the limit was hit is consumed either
+            // way, so that the next read resumes where the scanner stopped.
+            let (consumed, too_large) = this.scanner.process(input, buf);
+            Pin::new(&mut *this.r).consume(consumed);
+            if too_large {
+                if buf.filled().len() > start {
+                    break;
+                }
+                return Poll::Ready(Err(Error::other(ERR_DATA_TOO_LARGE)));
+            }
         }
-        
+
         Poll::Ready(Ok(()))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, BufReader};
+
+    /// Reads a message from `input` through a buffer of `capacity` bytes,
+    /// `read_size` bytes at a time, reading past the size limit once it is
+    /// hit. Returns the message, whether it was too large and what is left
+    /// of the input.
+    async fn read_message(input: &[u8], capacity: usize, read_size: usize, max: usize) -> (Vec<u8>, bool, Vec<u8>) {
+        let mut r = BufReader::with_capacity(capacity, input);
+        let mut body = Vec::new();
+        let mut dr = DataReader::new(&mut r, max);
+        let mut buf = vec![0; read_size];
+        loop {
+            match dr.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => body.extend_from_slice(&buf[..n]),
+                Err(err) => {
+                    assert!(dr.too_large(), "unexpected error: {}", err);
+                    dr.remove_limit();
+                }
+            }
+        }
+        let too_large = dr.too_large();
+
+        let mut rest = Vec::new();
+        r.read_to_end(&mut rest).await.unwrap();
+        (body, too_large, rest)
+    }
+
+    #[tokio::test]
+    async fn removes_dot_stuffing() {
+        let input = b"a\r\n..b\r\n.\r\nQUIT\r\n";
+        for capacity in [1, 2, 3, 64] {
+            let (body, too_large, rest) = read_message(input, capacity, 64, 0).await;
+            assert_eq!(body, b"a\r\n.b\r\n", "capacity {}", capacity);
+            assert!(!too_large);
+            assert_eq!(rest, b"QUIT\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_cr_after_dot_without_lf() {
+        let input = b"a\r\n.\rX\r\n.\r\nQUIT\r\n";
+        for capacity in [1, 2, 3, 64] {
+            let (body, _, rest) = read_message(input, capacity, 64, 0).await;
+            assert_eq!(body, b"a\r\n\rX\r\n", "capacity {}", capacity);
+            assert_eq!(rest, b"QUIT\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn bare_lf_does_not_end_lines() {
+        let cases: [(&[u8], &[u8]); 3] = [
+            (b"a\n.\nb\r\n.\r\nQUIT\r\n", b"a\n.\nb\r\n"),
+            // The dot is still removed as dot-stuffing.
+            (b"a\r\n.\nb\r\n.\r\nQUIT\r\n", b"a\r\n\nb\r\n"),
+            (b"a\n.\r\nb\r\n.\r\nQUIT\r\n", b"a\n.\r\nb\r\n"),
+        ];
+        for (input, expected) in cases {
+            for capacity in [1, 2, 3, 64] {
+                let (body, _, rest) = read_message(input, capacity, 64, 0).await;
+                assert_eq!(body, expected, "{:?}, capacity {}", input, capacity);
+                assert_eq!(rest, b"QUIT\r\n");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handles_chunk_starting_with_lf() {
+        // With a 3 byte buffer, the LFs of both lines start a chunk.
+        let input = b"ab\r\n.\r\nQUIT\r\n";
+        let (body, _, rest) = read_message(input, 3, 64, 0).await;
+        assert_eq!(body, b"ab\r\n");
+        assert_eq!(rest, b"QUIT\r\n");
+    }
+
+    #[tokio::test]
+    async fn limit_reached_at_line_end() {
+        let (body, too_large, rest) = read_message(b"abc\r\n.\r\nQUIT\r\n", 64, 64, 5).await;
+        assert_eq!(body, b"abc\r\n");
+        assert!(!too_large);
+        assert_eq!(rest, b"QUIT\r\n");
+
+        let (body, too_large, rest) = read_message(b"abc\r\nd\r\n.\r\nQUIT\r\n", 64, 64, 5).await;
+        assert_eq!(body, b"abc\r\nd\r\n");
+        assert!(too_large);
+        assert_eq!(rest, b"QUIT\r\n");
+    }
+
+    #[tokio::test]
+    async fn limit_hit_mid_input_resumes_in_place() {
+        let input = b"abc\r\n.\rX\r\n.\r\nQUIT\r\n";
+        for capacity in [1, 3, 64] {
+            for read_size in [1, 2, 64] {
+                let (body, too_large, rest) = read_message(input, capacity, read_size, 5).await;
+                assert_eq!(body, b"abc\r\n\rX\r\n", "capacity {}, read size {}", capacity, read_size);
+                assert!(too_large);
+                assert_eq!(rest, b"QUIT\r\n");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_on_eof_before_terminator() {
+        let mut r = BufReader::new(&b"abc\r\n"[..]);
+        let mut body = Vec::new();
+        let err = DataReader::new(&mut r, 0).read_to_end(&mut body).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,46 +1,173 @@
-use anyhow::{bail, Result};
-use tokio::io::{AsyncRead, AsyncReadExt};
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-const ERR_TOO_LONG_LINE: &str = "smtp: too long a line in input stream";
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, ReadBuf};
 
+pub const ERR_TOO_LONG_LINE: &str = "smtp: too long a line in input stream";
+
+/// A line was longer than the limit of a `LineLimitReader`.
+#[derive(Debug, thiserror::Error)]
+#[error("{}", ERR_TOO_LONG_LINE)]
+pub struct TooLongLine;
+
+/// Fails reads with `ERR_TOO_LONG_LINE` once a line grows past `line_limit`
+/// bytes, CRLF included, without buffering more than the underlying reader.
+/// A `line_limit` of zero means no limit.
 pub struct LineLimitReader<R: AsyncRead> {
     pub r: R,
     pub line_limit: usize,
 
     pub cur_line_length: usize,
+    /// Set once a too long line has been seen.
+    pub too_long: bool,
+    /// Discard the rest of too long lines, up to their line feed, instead
+    /// of failing reads. A CR right before the line feed is kept, so that
+    /// readers which only end lines on CRLF see the line end.
+    pub skip_long_lines: bool,
+    /// The number of bytes of too long lines skipped so far.
+    pub skipped: u64,
+    /// Reads fail with `ERR_TOO_LONG_LINE` once more than this many bytes
+    /// were skipped.
+    pub skip_limit: u64,
+
+    // Whether the last byte skipped was a CR, and whether it is to be handed
+    // out before the line feed that follows it.
+    skipped_cr: bool,
+    pending_cr: bool,
+
+    // The length of the slice last returned by `poll_fill_buf`, and whether
+    // it ends a line.
+    available: usize,
+    ends_line: bool,
 }
 
-impl<R: AsyncRead + Unpin> LineLimitReader<R> {
+impl<R: AsyncBufRead + Unpin> LineLimitReader<R> {
     pub fn new(r: R, line_limit: usize) -> Self {
         Self {
             r,
             line_limit,
             cur_line_length: 0,
+            too_long: false,
+            skip_long_lines: false,
+            skipped: 0,
+            skip_limit: u64::MAX,
+            skipped_cr: false,
+            pending_cr: false,
+            available: 0,
+            ends_line: false,
         }
     }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for LineLimitReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        while this.skip_long_lines && this.line_limit > 0 && this.cur_line_length >= this.line_limit {
+            let buf = futures::ready!(Pin::new(&mut this.r).poll_fill_buf(cx))?;
+            if buf.is_empty() {
+                break;
+            }
+            this.too_long = true;
+            let n = match buf.iter().position(|&c| c == b'\n') {
+                // The line feed is handed out below, to end the line.
+                Some(0) => {
+                    this.pending_cr = std::mem::take(&mut this.skipped_cr);
+                    break;
+                }
+                Some(i) => i,
+                None => buf.len(),
+            };
+            this.skipped_cr = buf[n - 1] == b'\r';
+            Pin::new(&mut this.r).consume(n);
+            this.skipped += n as u64;
+            if this.skipped > this.skip_limit {
+                return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, TooLongLine)));
+            }
+        }
 
-    async fn read(&mut self, b: &mut [u8]) -> Result<usize> {
-        if (self.cur_line_length > self.line_limit) && (self.line_limit > 0) {
-            bail!(ERR_TOO_LONG_LINE);
+        if this.pending_cr {
+            this.available = 1;
+            this.ends_line = false;
+            return Poll::Ready(Ok(&b"\r"[..]));
         }
 
-        let n = self.r.read(b).await?;
-        if self.line_limit == 0 {
-            return Ok(n);
+        let buf = futures::ready!(Pin::new(&mut this.r).poll_fill_buf(cx))?;
+        if this.line_limit == 0 || buf.is_empty() {
+            this.available = buf.len();
+            this.ends_line = false;
+            return Poll::Ready(Ok(buf));
         }
 
-        for chr in b[..n].iter() {
-            if *chr == b'\n' {
-                self.cur_line_length = 0;
-            }
+        // Hand out at most the rest of the current line, so that `consume`
+        // knows when a line ends.
+        let mut room = this.line_limit.saturating_sub(this.cur_line_length);
+        if this.skip_long_lines && room == 0 {
+            // Only the line feed of a skipped line is left.
+            room = 1;
+        }
+        let (n, ends_line) = match buf.iter().take(room).position(|&c| c == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (buf.len().min(room), false),
+        };
+        if n == 0 {
+            this.too_long = true;
+            return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, TooLongLine)));
+        }
 
-            self.cur_line_length += 1;
+        this.available = n;
+        this.ends_line = ends_line;
+        Poll::Ready(Ok(&buf[..n]))
+    }
 
-            if self.cur_line_length > self.line_limit {
-                bail!(ERR_TOO_LONG_LINE);
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        if this.pending_cr {
+            // The CR was already taken from the underlying reader.
+            if amt > 0 {
+                this.pending_cr = false;
+                this.available = 0;
+                this.cur_line_length += 1;
             }
+            return;
+        }
+        if this.ends_line && amt == this.available {
+            this.cur_line_length = 0;
+        } else {
+            this.cur_line_length += amt;
         }
+        this.available -= amt.min(this.available);
+        this.ends_line = this.ends_line && this.available > 0;
+        Pin::new(&mut this.r).consume(amt);
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for LineLimitReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let src = futures::ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = src.len().min(buf.remaining());
+        buf.put_slice(&src[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
 
-        return Ok(n);
+/// Discards input up to the end of the current line, without buffering it.
+pub async fn skip_line<R: AsyncBufRead + Unpin>(r: &mut R) -> std::io::Result<()> {
+    loop {
+        let buf = r.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|&c| c == b'\n') {
+            Some(i) => {
+                r.consume(i + 1);
+                return Ok(());
+            }
+            None => {
+                let n = buf.len();
+                r.consume(n);
+            }
+        }
     }
-}
\ No newline at end of file
+}
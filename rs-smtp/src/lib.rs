@@ -1,9 +1,19 @@
+pub mod address;
 pub mod backend;
 pub mod conn;
+pub mod metrics;
+pub mod ratelimit;
+pub mod registry;
 pub mod sasl;
 pub mod server;
+pub mod shutdown;
 
+mod bdat;
 mod data;
 mod lengthlimit_reader;
+mod limit;
+mod logging;
 mod parse;
-mod stream;
\ No newline at end of file
+mod proxy;
+mod stream;
+mod timeout;
\ No newline at end of file
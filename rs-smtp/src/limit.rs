@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+
+/// Groups client addresses for per-IP accounting. IPv4 addresses are
+/// counted on their own, IPv6 addresses by their network of `ipv6_prefix_len`
+/// bits, since a single client usually controls a whole /64 or more.
+pub fn client_key(ip: IpAddr, ipv6_prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return IpAddr::V4(v4);
+            }
+            let prefix_len = ipv6_prefix_len.min(128) as u32;
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts the concurrent connections of a server, overall and per client.
+#[derive(Clone, Default)]
+pub struct ConnLimiter {
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserves a slot for a new connection, or returns `None` if a limit is
+    /// reached. A limit of zero means no limit. The slot is released when
+    /// the returned permit is dropped.
+    pub fn acquire(&self, key: Option<IpAddr>, max_total: usize, max_per_ip: usize) -> Option<ConnPermit> {
+        let mut counts = self.counts.lock().unwrap();
+
+        if max_total > 0 && counts.total >= max_total {
+            return None;
+        }
+        if let Some(key) = key {
+            let n = counts.per_ip.get(&key).copied().unwrap_or(0);
+            if max_per_ip > 0 && n >= max_per_ip {
+                return None;
+            }
+            counts.per_ip.insert(key, n + 1);
+        }
+        counts.total += 1;
+
+        Some(ConnPermit {
+            limiter: self.clone(),
+            key,
+        })
+    }
+}
+
+/// A connection slot, released on drop.
+pub struct ConnPermit {
+    limiter: ConnLimiter,
+    key: Option<IpAddr>,
+}
+
+impl ConnPermit {
+    /// Counts the permit against client `key` too, for a connection whose
+    /// client wasn't known when the permit was taken, e.g. until its PROXY
+    /// protocol header arrived. Returns false if the client is already at
+    /// `max_per_ip` connections, zero meaning no limit.
+    pub fn set_key(&mut self, key: IpAddr, max_per_ip: usize) -> bool {
+        debug_assert!(self.key.is_none());
+        let mut counts = self.limiter.counts.lock().unwrap();
+        let n = counts.per_ip.get(&key).copied().unwrap_or(0);
+        if max_per_ip > 0 && n >= max_per_ip {
+            return false;
+        }
+        counts.per_ip.insert(key, n + 1);
+        self.key = Some(key);
+        true
+    }
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(key) = self.key {
+            if let Some(n) = counts.per_ip.get_mut(&key) {
+                *n -= 1;
+                if *n == 0 {
+                    counts.per_ip.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn groups_ipv6_clients_by_prefix() {
+        assert_eq!(client_key(ip("2001:db8:1:2:3:4:5:6"), 64), ip("2001:db8:1:2::"));
+        assert_eq!(client_key(ip("2001:db8:1:2:3:4:5:6"), 48), ip("2001:db8:1::"));
+        assert_eq!(client_key(ip("2001:db8:1:2:3:4:5:6"), 0), ip("::"));
+        assert_eq!(client_key(ip("2001:db8:1:2:3:4:5:6"), 128), ip("2001:db8:1:2:3:4:5:6"));
+        assert_eq!(client_key(ip("2001:db8:1:2:3:4:5:6"), 200), ip("2001:db8:1:2:3:4:5:6"));
+    }
+
+    #[test]
+    fn counts_ipv4_clients_alone() {
+        assert_eq!(client_key(ip("192.0.2.1"), 0), ip("192.0.2.1"));
+        assert_eq!(client_key(ip("::ffff:192.0.2.1"), 64), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+
+    #[test]
+    fn permits_are_released_on_drop() {
+        let limiter = ConnLimiter::new();
+        let (a, b, c) = (Some(ip("192.0.2.1")), Some(ip("192.0.2.2")), Some(ip("192.0.2.3")));
+
+        let first = limiter.acquire(a, 2, 1).unwrap();
+        assert!(limiter.acquire(a, 2, 1).is_none(), "per-IP limit");
+        let second = limiter.acquire(b, 2, 1).unwrap();
+        assert!(limiter.acquire(c, 2, 1).is_none(), "total limit");
+        assert!(limiter.acquire(None, 2, 1).is_none(), "total limit without an address");
+
+        drop(first);
+        {
+            let counts = limiter.counts.lock().unwrap();
+            assert_eq!(counts.total, 1);
+            assert!(!counts.per_ip.contains_key(&a.unwrap()));
+        }
+        let third = limiter.acquire(a, 2, 1).unwrap();
+
+        drop(second);
+        drop(third);
+        let counts = limiter.counts.lock().unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.per_ip.is_empty());
+    }
+
+    #[test]
+    fn keys_can_be_set_later() {
+        let limiter = ConnLimiter::new();
+        let key = ip("192.0.2.1");
+
+        let mut first = limiter.acquire(None, 0, 0).unwrap();
+        assert!(first.set_key(key, 1));
+        let mut second = limiter.acquire(None, 0, 0).unwrap();
+        assert!(!second.set_key(key, 1));
+        assert_eq!(limiter.counts.lock().unwrap().total, 2);
+
+        drop(second);
+        drop(first);
+        let counts = limiter.counts.lock().unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.per_ip.is_empty());
+    }
+
+    #[test]
+    fn zero_means_no_limit() {
+        let limiter = ConnLimiter::new();
+        let key = ip("192.0.2.1");
+        let permits: Vec<_> = (0..10).map(|_| limiter.acquire(Some(key), 0, 0).unwrap()).collect();
+        assert_eq!(limiter.counts.lock().unwrap().per_ip[&key], 10);
+        drop(permits);
+        assert_eq!(limiter.counts.lock().unwrap().total, 0);
+    }
+}
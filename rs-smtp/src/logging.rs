@@ -0,0 +1,57 @@
+use std::net::SocketAddr;
+
+/// The fields every log event of a connection carries, so a whole session
+/// can be followed in the logs.
+#[derive(Clone, Default)]
+pub struct LogContext {
+    pub conn_id: u64,
+    pub peer: Option<SocketAddr>,
+    /// The command being handled, empty between commands.
+    pub command: String,
+}
+
+impl LogContext {
+    pub fn peer(&self) -> String {
+        format_peer(self.peer)
+    }
+}
+
+/// Formats a peer address for the `peer` field of log events, with `-` for
+/// connections without one, such as Unix domain sockets.
+pub fn format_peer(addr: Option<SocketAddr>) -> String {
+    match addr {
+        Some(addr) => addr.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+/// Logs an event of a connection with the `conn_id`, `peer` and `command`
+/// fields of its `LogContext`, plus any extra key-value pairs:
+///
+/// `conn_log!(ctx, Level::Debug; "command")`
+/// `conn_log!(ctx, Level::Debug, code = 250; "reply")`
+macro_rules! conn_log {
+    ($ctx:expr, $lvl:expr, $($key:ident = $value:expr),+; $($arg:tt)+) => {{
+        let ctx: &$crate::logging::LogContext = $ctx;
+        log::log!(
+            $lvl,
+            conn_id = ctx.conn_id,
+            peer = ctx.peer().as_str(),
+            command = ctx.command.as_str(),
+            $($key = $value),+;
+            $($arg)+
+        )
+    }};
+    ($ctx:expr, $lvl:expr; $($arg:tt)+) => {{
+        let ctx: &$crate::logging::LogContext = $ctx;
+        log::log!(
+            $lvl,
+            conn_id = ctx.conn_id,
+            peer = ctx.peer().as_str(),
+            command = ctx.command.as_str();
+            $($arg)+
+        )
+    }};
+}
+
+pub(crate) use conn_log;
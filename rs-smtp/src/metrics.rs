@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The commands counted under their own name, anything else is counted as
+/// `UNKNOWN` so that clients can't grow the number of series.
+const KNOWN_COMMANDS: &[&str] = &[
+    "HELO", "EHLO", "LHLO", "MAIL", "RCPT", "DATA", "BDAT", "RSET", "NOOP", "VRFY", "QUIT",
+    "AUTH", "STARTTLS", "SEND", "SOML", "SAML", "EXPN", "HELP", "TURN",
+];
+
+/// Receives every metric update, e.g. to feed another metrics system.
+///
+/// With the `metrics` feature, `MetricsFacade` forwards updates to the
+/// `metrics` crate facade.
+pub trait MetricsHook: Send + Sync {
+    fn increment_counter(&self, name: &'static str, labels: &[(&'static str, String)], value: u64);
+
+    fn set_gauge(&self, name: &'static str, value: f64);
+}
+
+#[derive(Default)]
+struct Inner {
+    connections: AtomicU64,
+    connections_active: AtomicI64,
+    connections_rejected_busy: AtomicU64,
+    connections_rejected_rate_limited: AtomicU64,
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+    replies: Mutex<BTreeMap<u16, u64>>,
+    messages_accepted: AtomicU64,
+    message_bytes_accepted: AtomicU64,
+    auth_successes: AtomicU64,
+    auth_failures: AtomicU64,
+    tls_upgrades: AtomicU64,
+}
+
+/// Counters of a server's activity. It is cheap to clone, and clones share
+/// the same counters.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+    hook: Option<Arc<dyn MetricsHook>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates metrics which also pass every update to `hook`.
+    pub fn with_hook(hook: Arc<dyn MetricsHook>) -> Self {
+        Metrics {
+            inner: Arc::default(),
+            hook: Some(hook),
+        }
+    }
+
+    fn increment(&self, name: &'static str, labels: &[(&'static str, String)]) {
+        if let Some(hook) = &self.hook {
+            hook.increment_counter(name, labels, 1);
+        }
+    }
+
+    pub(crate) fn connection_opened(&self) -> ActiveConnection {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+        self.increment("smtp_connections_total", &[]);
+        self.add_active(1);
+        ActiveConnection {
+            metrics: self.clone(),
+        }
+    }
+
+    /// Counts a connection turned away before the greeting, because of the
+    /// connection limits (`busy`) or the rate limiter (`rate_limited`).
+    pub(crate) fn connection_rejected(&self, rate_limited: bool) {
+        let (counter, reason) = if rate_limited {
+            (&self.inner.connections_rejected_rate_limited, "rate_limited")
+        } else {
+            (&self.inner.connections_rejected_busy, "busy")
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.increment("smtp_connections_rejected_total", &[("reason", reason.to_string())]);
+    }
+
+    fn add_active(&self, n: i64) {
+        let active = self.inner.connections_active.fetch_add(n, Ordering::Relaxed) + n;
+        if let Some(hook) = &self.hook {
+            hook.set_gauge("smtp_connections_active", active as f64);
+        }
+    }
+
+    pub(crate) fn command(&self, cmd: &str) {
+        let cmd = KNOWN_COMMANDS.iter().find(|c| **c == cmd).copied().unwrap_or("UNKNOWN");
+        *self.inner.commands.lock().unwrap().entry(cmd).or_insert(0) += 1;
+        self.increment("smtp_commands_total", &[("command", cmd.to_string())]);
+    }
+
+    pub(crate) fn reply(&self, code: u16) {
+        *self.inner.replies.lock().unwrap().entry(code).or_insert(0) += 1;
+        self.increment("smtp_replies_total", &[("code", code.to_string())]);
+    }
+
+    pub(crate) fn message_accepted(&self, bytes: usize) {
+        self.inner.messages_accepted.fetch_add(1, Ordering::Relaxed);
+        self.inner.message_bytes_accepted.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.increment("smtp_messages_accepted_total", &[]);
+        if let Some(hook) = &self.hook {
+            hook.increment_counter("smtp_message_bytes_accepted_total", &[], bytes as u64);
+        }
+    }
+
+    pub(crate) fn auth(&self, success: bool) {
+        let (counter, result) = if success {
+            (&self.inner.auth_successes, "success")
+        } else {
+            (&self.inner.auth_failures, "failure")
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.increment("smtp_auth_attempts_total", &[("result", result.to_string())]);
+    }
+
+    pub(crate) fn tls_upgrade(&self) {
+        self.inner.tls_upgrades.fetch_add(1, Ordering::Relaxed);
+        self.increment("smtp_tls_upgrades_total", &[]);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format, to be
+    /// served on a `/metrics` endpoint.
+    pub fn render_prometheus(&self) -> String {
+        let inner = &self.inner;
+        let mut out = String::new();
+
+        write_metric(&mut out, "smtp_connections_total", "counter", "Connections handled.");
+        writeln!(out, "smtp_connections_total {}", inner.connections.load(Ordering::Relaxed)).unwrap();
+
+        write_metric(&mut out, "smtp_connections_active", "gauge", "Connections currently open.");
+        writeln!(out, "smtp_connections_active {}", inner.connections_active.load(Ordering::Relaxed)).unwrap();
+
+        write_metric(&mut out, "smtp_connections_rejected_total", "counter", "Connections turned away before the greeting, by reason.");
+        writeln!(out, "smtp_connections_rejected_total{{reason=\"busy\"}} {}", inner.connections_rejected_busy.load(Ordering::Relaxed)).unwrap();
+        writeln!(out, "smtp_connections_rejected_total{{reason=\"rate_limited\"}} {}", inner.connections_rejected_rate_limited.load(Ordering::Relaxed)).unwrap();
+
+        write_metric(&mut out, "smtp_commands_total", "counter", "Commands received, by command.");
+        for (cmd, n) in inner.commands.lock().unwrap().iter() {
+            writeln!(out, "smtp_commands_total{{command=\"{}\"}} {}", cmd, n).unwrap();
+        }
+
+        write_metric(&mut out, "smtp_replies_total", "counter", "Replies sent, by code.");
+        for (code, n) in inner.replies.lock().unwrap().iter() {
+            writeln!(out, "smtp_replies_total{{code=\"{}\"}} {}", code, n).unwrap();
+        }
+
+        write_metric(&mut out, "smtp_messages_accepted_total", "counter", "Messages accepted.");
+        writeln!(out, "smtp_messages_accepted_total {}", inner.messages_accepted.load(Ordering::Relaxed)).unwrap();
+
+        write_metric(&mut out, "smtp_message_bytes_accepted_total", "counter", "Bytes of accepted messages.");
+        writeln!(out, "smtp_message_bytes_accepted_total {}", inner.message_bytes_accepted.load(Ordering::Relaxed)).unwrap();
+
+        write_metric(&mut out, "smtp_auth_attempts_total", "counter", "Authentication attempts, by result.");
+        writeln!(out, "smtp_auth_attempts_total{{result=\"success\"}} {}", inner.auth_successes.load(Ordering::Relaxed)).unwrap();
+        writeln!(out, "smtp_auth_attempts_total{{result=\"failure\"}} {}", inner.auth_failures.load(Ordering::Relaxed)).unwrap();
+
+        write_metric(&mut out, "smtp_tls_upgrades_total", "counter", "Successful STARTTLS upgrades.");
+        writeln!(out, "smtp_tls_upgrades_total {}", inner.tls_upgrades.load(Ordering::Relaxed)).unwrap();
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Counts a connection as active until dropped.
+pub(crate) struct ActiveConnection {
+    metrics: Metrics,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.metrics.add_active(-1);
+    }
+}
+
+/// A `MetricsHook` forwarding to the `metrics` crate facade, and from there
+/// to whatever recorder is installed.
+#[cfg(feature = "metrics")]
+pub struct MetricsFacade;
+
+#[cfg(feature = "metrics")]
+impl MetricsHook for MetricsFacade {
+    fn increment_counter(&self, name: &'static str, labels: &[(&'static str, String)], value: u64) {
+        let labels: Vec<::metrics::Label> = labels
+            .iter()
+            .map(|(key, value)| ::metrics::Label::new(*key, value.clone()))
+            .collect();
+        ::metrics::counter!(name, labels).increment(value);
+    }
+
+    fn set_gauge(&self, name: &'static str, value: f64) {
+        ::metrics::gauge!(name).set(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::new();
+        let _active = metrics.connection_opened();
+        metrics.connection_rejected(true);
+        metrics.command("EHLO");
+        metrics.command("MAIL");
+        metrics.command("MAIL");
+        metrics.command("X-FOO");
+        metrics.reply(250);
+        metrics.reply(421);
+        metrics.message_accepted(100);
+        metrics.auth(false);
+
+        let out = metrics.render_prometheus();
+        for line in [
+            "# HELP smtp_connections_total Connections handled.",
+            "# TYPE smtp_connections_total counter",
+            "smtp_connections_total 1",
+            "# TYPE smtp_connections_active gauge",
+            "smtp_connections_active 1",
+            "smtp_connections_rejected_total{reason=\"busy\"} 0",
+            "smtp_connections_rejected_total{reason=\"rate_limited\"} 1",
+            "# TYPE smtp_commands_total counter",
+            "smtp_commands_total{command=\"EHLO\"} 1",
+            "smtp_commands_total{command=\"MAIL\"} 2",
+            "smtp_commands_total{command=\"UNKNOWN\"} 1",
+            "smtp_replies_total{code=\"250\"} 1",
+            "smtp_replies_total{code=\"421\"} 1",
+            "smtp_messages_accepted_total 1",
+            "smtp_message_bytes_accepted_total 100",
+            "smtp_auth_attempts_total{result=\"success\"} 0",
+            "smtp_auth_attempts_total{result=\"failure\"} 1",
+            "smtp_tls_upgrades_total 0",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {:?} in:\n{}", line, out);
+        }
+        assert!(!out.contains("X-FOO"));
+
+        drop(_active);
+        assert!(metrics.render_prometheus().lines().any(|l| l == "smtp_connections_active 0"));
+    }
+}
@@ -1,6 +1,11 @@
 use std::{collections::HashMap};
 use anyhow::{bail, Result};
 
+/// A command line which isn't valid UTF-8.
+#[derive(Debug, thiserror::Error)]
+#[error("smtp: command line is not valid UTF-8")]
+pub struct InvalidUtf8;
+
 pub fn parse_cmd(line: String) -> Result<(String, String)> {
     let line = line.trim_end_matches("\r\n");
 
@@ -20,7 +25,7 @@ pub fn parse_cmd(line: String) -> Result<(String, String)> {
         bail!("Mangled command: {}", line);
     }
 
-    if line.chars().nth(4) != Some(' ') {
+    if line.as_bytes()[4] != b' ' {
 		// There wasn't a space after the command?
 		bail!("Mangled command: {}", line);
 	}
@@ -28,25 +33,52 @@ pub fn parse_cmd(line: String) -> Result<(String, String)> {
     Ok((line[0..4].to_uppercase(), line[5..].trim_end_matches(" \r\n").to_string()))
 }
 
+/// Parses ESMTP parameters into a map from their uppercased keywords, which
+/// are case-insensitive, to their values. A keyword may only be given once.
 pub fn parse_args(args: &[&str]) -> Result<HashMap<String, String>> {
     let mut arg_map = HashMap::new();
 
     for arg in args {
-        if arg.len() == 0 {
+        if arg.is_empty() {
             continue;
         }
 
-        let mut parts = arg.split('=');
-        match (parts.next(), parts.next()) {
-            (Some(key), Some(value)) => {
-                arg_map.insert(key.to_string(), value.to_string());
-            }
-            (Some(key), None) => {
-                arg_map.insert(key.to_string(), "".to_string());
-            }
-            _ => bail!("Failed to parse arg string: {}", arg),
+        let (key, value) = match arg.split_once('=') {
+            Some(("", _)) => bail!("Failed to parse arg string: {}", arg),
+            Some((key, value)) => (key, value),
+            None => (*arg, ""),
+        };
+        if arg_map.insert(key.to_uppercase(), value.to_string()).is_some() {
+            bail!("Duplicate parameter: {}", key);
         }
     }
 
     Ok(arg_map)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_args() {
+        let args = parse_args(&["size=10", "BODY=8BITMIME", "SMTPUTF8", "ENVID=a=b"]).unwrap();
+        assert_eq!(args["SIZE"], "10");
+        assert_eq!(args["BODY"], "8BITMIME");
+        assert_eq!(args["SMTPUTF8"], "");
+        assert_eq!(args["ENVID"], "a=b");
+    }
+
+    #[test]
+    fn rejects_bad_args() {
+        assert!(parse_args(&["=10"]).is_err());
+        assert!(parse_args(&["RET=FULL", "ret=HDRS"]).is_err());
+    }
+
+    #[test]
+    fn parses_cmd() {
+        assert_eq!(parse_cmd("mail FROM:<a@b>\r\n".to_string()).unwrap(), ("MAIL".to_string(), "FROM:<a@b>".to_string()));
+        assert_eq!(parse_cmd("QUIT\r\n".to_string()).unwrap(), ("QUIT".to_string(), String::new()));
+        assert!(parse_cmd("MAILX\r\n".to_string()).is_err());
+        assert!(parse_cmd("HELOé\r\n".to_string()).is_err());
+    }
+}
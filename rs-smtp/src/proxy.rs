@@ -0,0 +1,221 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// A v1 header is at most 107 bytes, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The addresses of a proxied connection, as seen by the proxy.
+#[derive(Clone, Copy, Debug)]
+pub struct ProxyAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of a connection.
+/// Returns `None` if the proxy doesn't know the addresses, e.g. for its own
+/// health checks, in which case the connection's own addresses apply.
+///
+/// The header is read without buffering, so the stream is left at the first
+/// byte of the proxied connection.
+pub async fn read_header<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<ProxyAddrs>> {
+    // The shortest header, "PROXY UNKNOWN\r\n", is longer than the v2
+    // signature, so this never reads past the header.
+    let mut start = [0u8; 12];
+    r.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(r).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(r, &start).await
+    } else {
+        bail!("proxy: missing PROXY protocol header")
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(r: &mut R, start: &[u8]) -> Result<Option<ProxyAddrs>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!("proxy: v1 header too long");
+        }
+        line.push(r.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_| anyhow!("proxy: invalid v1 header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields[0] {
+        "UNKNOWN" => Ok(None),
+        "TCP4" | "TCP6" => {
+            if fields.len() != 5 {
+                bail!("proxy: invalid v1 header");
+            }
+            let source = parse_v1_addr(fields[1], fields[3])?;
+            let destination = parse_v1_addr(fields[2], fields[4])?;
+            if (fields[0] == "TCP4") != source.is_ipv4() || source.is_ipv4() != destination.is_ipv4() {
+                bail!("proxy: v1 address family mismatch");
+            }
+            Ok(Some(ProxyAddrs { source, destination }))
+        }
+        _ => bail!("proxy: unsupported v1 protocol {}", fields[0]),
+    }
+}
+
+fn parse_v1_addr(ip: &str, port: &str) -> Result<SocketAddr> {
+    let ip: IpAddr = ip.parse().map_err(|_| anyhow!("proxy: invalid v1 address {}", ip))?;
+    let port: u16 = port.parse().map_err(|_| anyhow!("proxy: invalid v1 port {}", port))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<ProxyAddrs>> {
+    let ver_cmd = r.read_u8().await?;
+    let family = r.read_u8().await?;
+    let len = r.read_u16().await? as usize;
+
+    let mut body = vec![0u8; len];
+    r.read_exact(&mut body).await?;
+
+    if ver_cmd >> 4 != 2 {
+        bail!("proxy: unsupported version {}", ver_cmd >> 4);
+    }
+    match ver_cmd & 0x0f {
+        // LOCAL: the proxy's own connection.
+        0 => return Ok(None),
+        1 => {}
+        cmd => bail!("proxy: unsupported v2 command {}", cmd),
+    }
+
+    match family {
+        // TCP over IPv4
+        0x11 => {
+            if body.len() < 12 {
+                bail!("proxy: v2 header too short");
+            }
+            let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Ok(Some(ProxyAddrs {
+                source: SocketAddr::new(src.into(), u16::from_be_bytes([body[8], body[9]])),
+                destination: SocketAddr::new(dst.into(), u16::from_be_bytes([body[10], body[11]])),
+            }))
+        }
+        // TCP over IPv6
+        0x21 => {
+            if body.len() < 36 {
+                bail!("proxy: v2 header too short");
+            }
+            let src: [u8; 16] = body[0..16].try_into().unwrap();
+            let dst: [u8; 16] = body[16..32].try_into().unwrap();
+            Ok(Some(ProxyAddrs {
+                source: SocketAddr::new(Ipv6Addr::from(src).into(), u16::from_be_bytes([body[32], body[33]])),
+                destination: SocketAddr::new(Ipv6Addr::from(dst).into(), u16::from_be_bytes([body[34], body[35]])),
+            }))
+        }
+        // Unspecified or Unix sockets carry no usable address.
+        0x00 | 0x31 => Ok(None),
+        _ => bail!("proxy: unsupported v2 address family {:#04x}", family),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a header from `input`, returning what is left of it too.
+    async fn read(input: &[u8]) -> (Result<Option<ProxyAddrs>>, &[u8]) {
+        let mut r = input;
+        let res = read_header(&mut r).await;
+        (res, r)
+    }
+
+    fn v2_header(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut h = V2_SIGNATURE.to_vec();
+        h.extend_from_slice(&[ver_cmd, family]);
+        h.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        h.extend_from_slice(body);
+        h
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (res, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 5555 25\r\nEHLO x\r\n").await;
+        let addrs = res.unwrap().unwrap();
+        assert_eq!(addrs.source, "192.0.2.1:5555".parse().unwrap());
+        assert_eq!(addrs.destination, "198.51.100.2:25".parse().unwrap());
+        assert_eq!(rest, b"EHLO x\r\n");
+
+        let (res, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 5555 25\r\n").await;
+        assert_eq!(res.unwrap().unwrap().source, "[2001:db8::1]:5555".parse().unwrap());
+
+        let (res, rest) = read(b"PROXY UNKNOWN\r\nEHLO x\r\n").await;
+        assert!(res.unwrap().is_none());
+        assert_eq!(rest, b"EHLO x\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v1_headers() {
+        let too_long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        for input in [
+            "EHLO x\r\nQUIT\r\n",
+            "PROXY TCP4 192.0.2.1 198.51.100.2 5555\r\n",
+            "PROXY TCP4 192.0.2.1 198.51.100.2 5555 99999\r\n",
+            "PROXY TCP4 2001:db8::1 198.51.100.2 5555 25\r\n",
+            "PROXY TCP6 192.0.2.1 198.51.100.2 5555 25\r\n",
+            "PROXY UDP4 192.0.2.1 198.51.100.2 5555 25\r\n",
+            "PROXY TCP4 192.0.2.é 198.51.100.2 5555 25\r\n",
+            "PROXY TCP4 192.0.2.1",
+            too_long.as_str(),
+        ] {
+            assert!(read(input.as_bytes()).await.0.is_err(), "{:?}", input);
+        }
+        assert!(read(b"PROXY TCP4 \xff\xfe 1 2\r\n").await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut input = v2_header(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 2, 0x15, 0xb3, 0, 25]);
+        input.extend_from_slice(b"EHLO x\r\n");
+        let (res, rest) = read(&input).await;
+        let addrs = res.unwrap().unwrap();
+        assert_eq!(addrs.source, "192.0.2.1:5555".parse().unwrap());
+        assert_eq!(addrs.destination, "198.51.100.2:25".parse().unwrap());
+        assert_eq!(rest, b"EHLO x\r\n");
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&[0x15, 0xb3, 0, 25]);
+        // Extra TLVs after the addresses are skipped.
+        body.extend_from_slice(&[0x04, 0, 1, 0]);
+        let input = v2_header(0x21, 0x21, &body);
+        let (res, rest) = read(&input).await;
+        assert_eq!(res.unwrap().unwrap().destination, "[2001:db8::2]:25".parse().unwrap());
+        assert!(rest.is_empty());
+
+        let (res, _) = read(&v2_header(0x20, 0x11, &[0; 12])).await;
+        assert!(res.unwrap().is_none());
+        let (res, _) = read(&v2_header(0x21, 0x00, &[])).await;
+        assert!(res.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v2_headers() {
+        // Too short for their address family.
+        assert!(read(&v2_header(0x21, 0x11, &[192, 0, 2, 1])).await.0.is_err());
+        assert!(read(&v2_header(0x21, 0x21, &[0; 12])).await.0.is_err());
+        // Unsupported version, command and family.
+        assert!(read(&v2_header(0x11, 0x11, &[0; 12])).await.0.is_err());
+        assert!(read(&v2_header(0x22, 0x11, &[0; 12])).await.0.is_err());
+        assert!(read(&v2_header(0x21, 0x12, &[0; 12])).await.0.is_err());
+
+        // Truncated headers.
+        let input = v2_header(0x21, 0x11, &[0; 12]);
+        for len in [4, 12, 14, 16, 20] {
+            assert!(read(&input[..len]).await.0.is_err(), "{} bytes", len);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use async_trait::async_trait;
+
+/// Rate limits per client, as a number of events per minute. A limit of zero
+/// means no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimits {
+    pub connections_per_minute: u32,
+    pub recipients_per_minute: u32,
+}
+
+/// Keeps the per-client rate limiting state of a server. Clients are keyed by
+/// their IP address, with IPv6 addresses grouped by `Server::ipv6_prefix_len`.
+///
+/// Implementations may keep their state in memory, like `MemoryRateLimiter`,
+/// or share it between several server instances.
+#[async_trait]
+pub trait RateLimiter: Send + Sync {
+    /// Called for each new connection, before the greeting. Returns false if
+    /// the client opened too many connections.
+    async fn allow_connection(&self, client: IpAddr) -> bool;
+
+    /// Called for each RCPT command. Returns false if the client sent too
+    /// many recipients.
+    async fn allow_recipient(&self, client: IpAddr) -> bool;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Connection,
+    Recipient,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Refills the bucket and takes a token if there is one. The bucket holds
+    /// up to `per_minute` tokens and refills at that rate.
+    fn take(&mut self, per_minute: u32, now: Instant) -> bool {
+        let capacity = per_minute as f64;
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn is_full(&self, per_minute: u32, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * per_minute as f64 / 60.0 >= per_minute as f64
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<(Kind, IpAddr), TokenBucket>,
+    prune_at: usize,
+}
+
+const MIN_PRUNE_AT: usize = 1024;
+
+/// A `RateLimiter` keeping token buckets in memory.
+pub struct MemoryRateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<Buckets>,
+}
+
+impl MemoryRateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        MemoryRateLimiter {
+            limits,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                prune_at: MIN_PRUNE_AT,
+            }),
+        }
+    }
+
+    fn limit(&self, kind: Kind) -> u32 {
+        match kind {
+            Kind::Connection => self.limits.connections_per_minute,
+            Kind::Recipient => self.limits.recipients_per_minute,
+        }
+    }
+
+    fn take(&self, kind: Kind, client: IpAddr) -> bool {
+        let per_minute = self.limit(kind);
+        if per_minute == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Full buckets hold no information, drop them once in a while so
+        // the map doesn't grow with every client ever seen.
+        if buckets.buckets.len() >= buckets.prune_at {
+            buckets.buckets.retain(|(kind, _), b| !b.is_full(self.limit(*kind), now));
+            buckets.prune_at = (buckets.buckets.len() * 2).max(MIN_PRUNE_AT);
+        }
+
+        buckets.buckets
+            .entry((kind, client))
+            .or_insert(TokenBucket {
+                tokens: per_minute as f64,
+                updated: now,
+            })
+            .take(per_minute, now)
+    }
+}
+
+#[async_trait]
+impl RateLimiter for MemoryRateLimiter {
+    async fn allow_connection(&self, client: IpAddr) -> bool {
+        self.take(Kind::Connection, client)
+    }
+
+    async fn allow_recipient(&self, client: IpAddr) -> bool {
+        self.take(Kind::Recipient, client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_refills_at_the_limit_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket {
+            tokens: 2.0,
+            updated: start,
+        };
+        assert!(bucket.take(2, start));
+        assert!(bucket.take(2, start));
+        assert!(!bucket.take(2, start));
+
+        // One token per 30 seconds.
+        assert!(!bucket.take(2, start + Duration::from_secs(20)));
+        assert!(bucket.take(2, start + Duration::from_secs(31)));
+        assert!(!bucket.take(2, start + Duration::from_secs(31)));
+
+        // The bucket never holds more than the limit.
+        let later = start + Duration::from_secs(3600);
+        assert!(bucket.is_full(2, later));
+        assert!(bucket.take(2, later));
+        assert!(bucket.take(2, later));
+        assert!(!bucket.take(2, later));
+    }
+
+    #[tokio::test]
+    async fn limits_each_client_and_kind() {
+        let limiter = MemoryRateLimiter::new(RateLimits {
+            connections_per_minute: 2,
+            recipients_per_minute: 1,
+        });
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert!(limiter.allow_connection(a).await);
+        assert!(limiter.allow_connection(a).await);
+        assert!(!limiter.allow_connection(a).await);
+        assert!(limiter.allow_connection(b).await);
+
+        assert!(limiter.allow_recipient(a).await);
+        assert!(!limiter.allow_recipient(a).await);
+        assert!(limiter.allow_recipient(b).await);
+    }
+
+    #[tokio::test]
+    async fn zero_means_no_limit() {
+        let limiter = MemoryRateLimiter::new(RateLimits::default());
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..100 {
+            assert!(limiter.allow_connection(a).await);
+            assert!(limiter.allow_recipient(a).await);
+        }
+        assert!(limiter.buckets.lock().unwrap().buckets.is_empty());
+    }
+}
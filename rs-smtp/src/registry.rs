@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tokio::sync::Notify;
+
+use crate::stream::ByteCounters;
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Where a connection is in the mail transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionState {
+    /// No transaction has been started.
+    Idle,
+    /// MAIL has been accepted.
+    Mail,
+    /// At least one RCPT has been accepted.
+    Rcpt,
+    /// The message is being received with DATA.
+    Data,
+    /// The message is being received with BDAT.
+    Bdat,
+}
+
+/// A snapshot of a live connection.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub connected_at: SystemTime,
+    pub helo: String,
+    pub tls: bool,
+    pub auth_identity: Option<String>,
+    pub state: TransactionState,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+struct TrackedState {
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    helo: String,
+    tls: bool,
+    auth_identity: Option<String>,
+    state: TransactionState,
+}
+
+/// The live state of a connection shared between the `Conn` and the
+/// registry.
+pub(crate) struct ConnTracker {
+    id: u64,
+    connected_at: SystemTime,
+    state: Mutex<TrackedState>,
+    counters: Arc<ByteCounters>,
+    kick: Notify,
+}
+
+impl ConnTracker {
+    pub fn new(counters: Arc<ByteCounters>) -> Self {
+        ConnTracker {
+            id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            connected_at: SystemTime::now(),
+            state: Mutex::new(TrackedState {
+                peer_addr: None,
+                local_addr: None,
+                helo: String::new(),
+                tls: false,
+                auth_identity: None,
+                state: TransactionState::Idle,
+            }),
+            counters,
+            kick: Notify::new(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_addrs(&self, peer_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) {
+        let mut state = self.state.lock().unwrap();
+        state.peer_addr = peer_addr;
+        state.local_addr = local_addr;
+    }
+
+    pub fn set_helo(&self, helo: &str) {
+        self.state.lock().unwrap().helo = helo.to_string();
+    }
+
+    pub fn set_tls(&self, tls: bool) {
+        self.state.lock().unwrap().tls = tls;
+    }
+
+    pub fn set_auth_identity(&self, identity: Option<String>) {
+        self.state.lock().unwrap().auth_identity = identity;
+    }
+
+    pub fn set_state(&self, state: TransactionState) {
+        self.state.lock().unwrap().state = state;
+    }
+
+    /// Resolves once the connection has been kicked from the registry.
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+
+    fn info(&self) -> ConnectionInfo {
+        let state = self.state.lock().unwrap();
+        ConnectionInfo {
+            id: self.id,
+            peer_addr: state.peer_addr,
+            local_addr: state.local_addr,
+            connected_at: self.connected_at,
+            helo: state.helo.clone(),
+            tls: state.tls,
+            auth_identity: state.auth_identity.clone(),
+            state: state.state,
+            bytes_read: self.counters.read.load(Ordering::Relaxed),
+            bytes_written: self.counters.written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The live connections of a `Server`. It is cheap to clone, and clones
+/// share the same connections.
+#[derive(Clone, Default)]
+pub struct Registry {
+    conns: Arc<Mutex<HashMap<u64, Arc<ConnTracker>>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lists the live connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut conns: Vec<ConnectionInfo> = self.conns.lock().unwrap()
+            .values()
+            .map(|c| c.info())
+            .collect();
+        conns.sort_by_key(|c| c.id);
+        conns
+    }
+
+    pub fn get(&self, id: u64) -> Option<ConnectionInfo> {
+        self.conns.lock().unwrap().get(&id).map(|c| c.info())
+    }
+
+    /// Forcibly disconnects a connection, even in the middle of a message
+    /// transfer. The client gets a 421 reply before the connection is
+    /// closed. Returns false if there is no such connection.
+    pub fn disconnect(&self, id: u64) -> bool {
+        match self.conns.lock().unwrap().get(&id) {
+            Some(c) => {
+                c.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn register(&self, tracker: Arc<ConnTracker>) -> RegistryGuard {
+        let id = tracker.id();
+        self.conns.lock().unwrap().insert(id, tracker);
+        RegistryGuard {
+            registry: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Removes a connection from the registry when dropped.
+pub(crate) struct RegistryGuard {
+    registry: Registry,
+    id: u64,
+}
+
+impl Drop for RegistryGuard {
+    fn drop(&mut self) {
+        self.registry.conns.lock().unwrap().remove(&self.id);
+    }
+}
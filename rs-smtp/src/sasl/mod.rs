@@ -1,5 +1,6 @@
 pub mod anonymous;
 pub mod plain;
+#[allow(clippy::module_inception)]
 pub mod sasl;
 
 pub use sasl::*;
@@ -17,11 +17,12 @@ pub trait PlainAuthenticator: Send + Sync {
 
 pub struct PlainServer<PA: PlainAuthenticator> {
     authenticator: PA,
+    identity: Option<String>,
 }
 
 impl <PA: PlainAuthenticator> PlainServer<PA> {
     pub fn new(authenticator: PA) -> Self {
-        Self { authenticator }
+        Self { authenticator, identity: None }
     }
 }
 
@@ -44,7 +45,12 @@ impl<PA: PlainAuthenticator> sasl::Server for PlainServer<PA> {
         let password = std::str::from_utf8(parts.next().ok_or_else(|| anyhow!("sasl: missing password"))?)?;
 
         self.authenticator.authenticate(identity, username, password).await?;
+        self.identity = Some(if identity.is_empty() { username } else { identity }.to_string());
 
         Ok((Vec::new(), true))
     }
+
+    fn identity(&self) -> Option<String> {
+        self.identity.clone()
+    }
 }
\ No newline at end of file
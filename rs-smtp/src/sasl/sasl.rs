@@ -15,4 +15,10 @@ pub trait Server: Send + Sync {
     /// If the authentication is finished, done is set to true. If the
     /// authentication has failed, an error is returned.
     async fn next(&mut self, response: Option<&[u8]>) -> Result<(Vec<u8>, bool)>;
+
+    /// The identity the client authenticated as, once authentication
+    /// succeeded.
+    fn identity(&self) -> Option<String> {
+        None
+    }
 }
\ No newline at end of file
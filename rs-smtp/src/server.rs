@@ -1,16 +1,43 @@
 use crate::backend::Backend;
-use crate::conn::Conn;
-use crate::parse::parse_cmd;
+use crate::conn::{Conn, Transport};
+use crate::lengthlimit_reader::TooLongLine;
+use crate::limit::{client_key, ConnLimiter};
+use crate::logging::{conn_log, format_peer};
+use crate::metrics::Metrics;
+use crate::parse::{parse_cmd, InvalidUtf8};
+use crate::proxy;
+use crate::ratelimit::RateLimiter;
+use crate::registry::Registry;
+use crate::shutdown::{self, ShutdownHandle};
+use crate::timeout::{deadline, with_timeout};
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use log::Level;
+pub use ipnet::IpNet;
 
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinSet;
+use tokio::time::error::Elapsed;
 use tokio_rustls::TlsAcceptor;
 
 
-// const ERR_TCP_AND_LMTP: &str = "smtp: cannot start LMTP server listening on a TCP socket";
+const ERR_TLS_WITHOUT_ACCEPTOR: &str = "smtp: cannot serve implicit TLS without a TLS acceptor";
+
+/// How long connections have to close once `shutdown_timeout` elapsed.
+const FORCE_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a proxy has to send the PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Server<B: Backend> {
     pub addr: String,
@@ -23,25 +50,132 @@ pub struct Server<B: Backend> {
     pub allow_insecure_auth: bool,
     pub strict: bool,
 
+    /// The number of protocol errors after which a client is disconnected
+    /// with `421 4.7.0 Too many errors`, 0 for no limit.
+    pub max_errors: usize,
+    /// When non-zero, replies to protocol errors and rejected recipients are
+    /// delayed by this much times the number of errors so far (tarpitting).
+    pub tarpit_delay: Duration,
+    /// The longest tarpitting delay, 0 for no cap.
+    pub tarpit_max_delay: Duration,
+
+    /// The maximum number of concurrent connections, 0 for no limit.
+    pub max_connections: usize,
+    /// The maximum number of concurrent connections per client, 0 for no
+    /// limit. IPv6 clients are grouped by `ipv6_prefix_len`.
+    pub max_connections_per_ip: usize,
+    /// The prefix length IPv6 client addresses are grouped by for per-client
+    /// limits.
+    pub ipv6_prefix_len: u8,
+    /// The maximum number of messages a client may send on one connection,
+    /// 0 for no limit.
+    pub max_messages_per_connection: usize,
+    /// Limits how often each client may connect and add recipients. See
+    /// `MemoryRateLimiter`.
+    pub rate_limiter: Option<Arc<dyn RateLimiter>>,
+
+    /// Expect a PROXY protocol (v1 or v2) header at the start of each
+    /// connection, as sent by HAProxy and most TCP load balancers, and use
+    /// the client address it carries instead of the proxy's. Connections
+    /// waiting for their header count towards `max_connections`.
+    pub proxy_protocol: bool,
+    /// The networks proxies may connect from when `proxy_protocol` is set.
+    /// Connections from other addresses are closed. Unix socket connections
+    /// are always trusted.
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// How long to wait for each command, 0 for no timeout. RFC 5321
+    /// asks for at least 5 minutes.
     pub read_timeout: Duration,
+    /// How long a client has to send its first command after the greeting,
+    /// 0 to use `read_timeout`.
+    pub greeting_timeout: Duration,
+    /// How long a client may go silent in the middle of a DATA or BDAT
+    /// transfer, 0 for no timeout.
+    pub data_block_timeout: Duration,
+    /// How long the backend has to handle a message once the client sent
+    /// all of it, 0 for no timeout.
+    pub data_termination_timeout: Duration,
+    /// How long a whole session may last, 0 for no limit.
+    pub session_timeout: Duration,
+    /// How long writing a reply may take, 0 for no timeout.
     pub write_timeout: Duration,
+    /// How long a client has to complete the TLS handshake of an implicit
+    /// TLS connection or after STARTTLS, 0 for no timeout. A handshake in
+    /// progress holds one of the `max_connections` slots.
+    pub tls_handshake_timeout: Duration,
+    /// How long transactions in progress may run after a shutdown was
+    /// triggered before their connections are closed, 0 for no limit.
+    pub shutdown_timeout: Duration,
 
     pub enable_smtputf8: bool,
     pub enable_requiretls: bool,
     pub enable_binarymime: bool,
+    /// Advertise DSN (RFC 3461) and accept its MAIL and RCPT parameters.
+    pub enable_dsn: bool,
+
+    /// Speak LMTP (RFC 2033) instead of SMTP: clients greet with LHLO and
+    /// get one reply per recipient after DATA.
+    pub lmtp: bool,
 
     pub backend: B,
 
+    /// Counters of the server's activity, see `Metrics::render_prometheus`.
+    /// Replace with `Metrics::with_hook` to feed another metrics system.
+    pub metrics: Metrics,
+
     pub caps: Vec<String>,
 
     //pub listeners: Mutex<Vec<TcpListener>>,
 
-    //pub conns: HashMap<String, Arc<Mutex<Conn<B, S>>>>,
+    registry: Registry,
+    shutdown: ShutdownHandle,
+    conn_limiter: ConnLimiter,
+}
+
+/// A listener the server can accept connections from.
+trait Listener: Send + 'static {
+    type Stream: Transport;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Stream, Option<SocketAddr>)>>;
+
+    fn local_addr(_stream: &Self::Stream) -> Option<SocketAddr> {
+        None
+    }
+
+    fn new_conn<B: Backend>(stream: Self::Stream, max_line_length: usize) -> Conn<B, Self::Stream> {
+        Conn::new(stream, max_line_length)
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(TcpStream, Option<SocketAddr>)>> {
+        TcpListener::poll_accept(self, cx).map_ok(|(stream, addr)| (stream, Some(addr)))
+    }
+
+    fn local_addr(stream: &TcpStream) -> Option<SocketAddr> {
+        stream.local_addr().ok()
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(UnixStream, Option<SocketAddr>)>> {
+        UnixListener::poll_accept(self, cx).map_ok(|(stream, _)| (stream, None))
+    }
+
+    fn new_conn<B: Backend>(stream: UnixStream, max_line_length: usize) -> Conn<B, UnixStream> {
+        Conn::new_unix(stream, max_line_length)
+    }
 }
 
 impl<B: Backend> Server<B> {
     pub fn new(be: B) -> Self {
-        return Server{
+        Server{
             addr: String::new(),
             tls_acceptor: None,
             domain: String::new(),
@@ -50,79 +184,346 @@ impl<B: Backend> Server<B> {
             max_line_length: 2000,
             allow_insecure_auth: true,
             strict: false,
-            read_timeout: Duration::from_secs(0),
+            max_errors: 0,
+            tarpit_delay: Duration::from_secs(0),
+            tarpit_max_delay: Duration::from_secs(30),
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            ipv6_prefix_len: 64,
+            max_messages_per_connection: 0,
+            rate_limiter: None,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            read_timeout: Duration::from_secs(5 * 60),
+            greeting_timeout: Duration::from_secs(0),
+            data_block_timeout: Duration::from_secs(3 * 60),
+            data_termination_timeout: Duration::from_secs(10 * 60),
+            session_timeout: Duration::from_secs(0),
             write_timeout: Duration::from_secs(0),
+            tls_handshake_timeout: Duration::from_secs(30),
+            shutdown_timeout: Duration::from_secs(30),
             enable_smtputf8: false,
             enable_requiretls: false,
             enable_binarymime: false,
+            enable_dsn: false,
+            lmtp: false,
             backend: be,
+            metrics: Metrics::new(),
             caps: vec!["PIPELINING".to_string(), "8BITMIME".to_string(), "ENHANCEDSTATUSCODES".to_string(), "CHUNKING".to_string()],
             //listeners: Mutex::new(vec![]),
+            registry: Registry::new(),
+            shutdown: ShutdownHandle::new(),
+            conn_limiter: ConnLimiter::new(),
         }
     }
 
+    /// Returns the registry of live connections, to inspect them or to
+    /// disconnect abusive clients.
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+
+    /// Returns a handle to gracefully shut the server down. Take it before
+    /// handing the server to one of the `serve` or `listen_and_serve`
+    /// methods, which return once all connections are drained.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
     pub async fn serve(self: Arc<Self>, l: TcpListener) -> Result<()> {
+        self.accept_loop(l, false).await
+    }
+
+    /// Serves implicit TLS (SMTPS) connections, as recommended by RFC 8314.
+    /// The TLS handshake is performed with `tls_acceptor` before the greeting
+    /// is sent.
+    pub async fn serve_tls(self: Arc<Self>, l: TcpListener) -> Result<()> {
+        if self.tls_acceptor.is_none() {
+            bail!(ERR_TLS_WITHOUT_ACCEPTOR);
+        }
+        self.accept_loop(l, true).await
+    }
+
+    async fn accept_loop<L: Listener>(self: Arc<Self>, l: L, implicit_tls: bool) -> Result<()> {
+        let mut shutdown = self.shutdown.subscribe();
+        let mut conns = JoinSet::new();
+
         loop {
-            match l.accept().await {
-                Ok((stream, _)) => {
-                    let server = self.clone();
-                    println!("New connection");
-                    tokio::spawn(async move {
-                        if let Err(err) = server.handle_conn(Conn::new(stream, server.max_line_length)).await {
-                            println!("Error333: {}", err);
-                        }
-                    });
-                }
-                Err(e) => {
-                    println!("Error444: {}", e);
-                }
+            tokio::select! {
+                _ = shutdown::signaled(&mut shutdown) => break,
+                Some(_) = conns.join_next(), if !conns.is_empty() => {}
+                res = poll_fn(|cx| l.poll_accept(cx)) => match res {
+                    Ok((mut stream, mut peer_addr)) => {
+                        let server = self.clone();
+
+                        conns.spawn(async move {
+                            let mut local_addr = L::local_addr(&stream);
+
+                            // Behind a proxy, the client is only known once the
+                            // header arrives, but the connection counts towards
+                            // the total from the start.
+                            let max_per_ip = if server.proxy_protocol { 0 } else { server.max_connections_per_ip };
+                            let mut key = peer_addr.map(|addr| client_key(addr.ip(), server.ipv6_prefix_len));
+                            let mut permit = server.conn_limiter.acquire(
+                                key.filter(|_| !server.proxy_protocol),
+                                server.max_connections,
+                                max_per_ip,
+                            );
+
+                            if server.proxy_protocol && permit.is_some() {
+                                match server.read_proxy_header(&mut stream, peer_addr).await {
+                                    Ok(Some(addrs)) => {
+                                        peer_addr = Some(addrs.source);
+                                        local_addr = Some(addrs.destination);
+                                    }
+                                    Ok(None) => {}
+                                    Err(err) => {
+                                        log::warn!(peer = format_peer(peer_addr).as_str(); "PROXY protocol error: {}", err);
+                                        return;
+                                    }
+                                }
+                                key = peer_addr.map(|addr| client_key(addr.ip(), server.ipv6_prefix_len));
+                                if let (Some(p), Some(key)) = (permit.as_mut(), key) {
+                                    if !p.set_key(key, server.max_connections_per_ip) {
+                                        permit = None;
+                                    }
+                                }
+                            }
+
+                            // Telling an implicit TLS client why would take the
+                            // handshake the limit is meant to spare.
+                            if permit.is_none() && implicit_tls {
+                                log::info!(peer = format_peer(peer_addr).as_str(); "too many connections, closing before the TLS handshake");
+                                server.metrics.connection_rejected(false);
+                                return;
+                            }
+
+                            let mut conn: Conn<B, L::Stream> = if implicit_tls {
+                                let acceptor = server.tls_acceptor.clone().unwrap();
+                                match with_timeout(server.tls_handshake_timeout, acceptor.accept(stream)).await {
+                                    Ok(Ok(stream)) => Conn::new_tls(stream, server.max_line_length),
+                                    Ok(Err(err)) => {
+                                        log::warn!(peer = format_peer(peer_addr).as_str(); "TLS handshake error: {}", err);
+                                        return;
+                                    }
+                                    Err(_) => {
+                                        log::info!(peer = format_peer(peer_addr).as_str(); "TLS handshake timeout");
+                                        return;
+                                    }
+                                }
+                            } else {
+                                L::new_conn(stream, server.max_line_length)
+                            };
+                            conn.set_addrs(peer_addr, local_addr);
+                            conn_log!(conn.log(), Level::Info, tls = implicit_tls; "connection accepted");
+
+                            let rate_limited = match (&server.rate_limiter, key) {
+                                (Some(limiter), Some(key)) if permit.is_some() => !limiter.allow_connection(key).await,
+                                _ => false,
+                            };
+                            if permit.is_none() || rate_limited {
+                                // handle_conn sets the metrics otherwise, this
+                                // counts the 421 of rejected connections.
+                                conn.stream.get_mut().metrics = Some(server.metrics.clone());
+                                server.metrics.connection_rejected(rate_limited);
+                                if rate_limited {
+                                    conn.reject_rate_limited().await;
+                                } else {
+                                    conn.reject().await;
+                                }
+                                return;
+                            }
+
+                            // The permit is held until the connection ends.
+                            let _permit = permit;
+
+                            // Errors are logged by the connection itself.
+                            let _ = server.handle_conn(conn).await;
+                        });
+                    }
+                    Err(err) => {
+                        log::error!("accept error: {}", err);
+                    }
+                },
             }
         }
+
+        // Stop accepting, then give the remaining connections until the
+        // deadline to finish their transactions.
+        drop(l);
+        let drained = with_timeout(self.shutdown_timeout, async {
+            while conns.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            // Connections close themselves, logging their sessions out. Only
+            // those not served yet, e.g. in a TLS handshake, are dropped.
+            self.shutdown.force_close();
+            let closed = tokio::time::timeout(FORCE_CLOSE_TIMEOUT, async {
+                while conns.join_next().await.is_some() {}
+            })
+            .await;
+            if closed.is_err() {
+                conns.shutdown().await;
+            }
+        }
+
+        Ok(())
     }
 
-    pub async fn handle_conn(&self, mut c: Conn<B>) -> Result<()> {
+    /// Reads the PROXY protocol header of a connection from `peer_addr`,
+    /// which must be a trusted proxy.
+    async fn read_proxy_header<T: Transport>(&self, stream: &mut T, peer_addr: Option<SocketAddr>) -> Result<Option<proxy::ProxyAddrs>> {
+        if let Some(addr) = peer_addr {
+            let ip = addr.ip().to_canonical();
+            if !self.trusted_proxies.iter().any(|net| net.contains(&ip)) {
+                bail!("proxy: connection from untrusted address {}", ip);
+            }
+        }
+        tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(stream)).await?
+    }
+
+    /// Serves connections on a Unix domain socket, e.g. for local LMTP
+    /// delivery or content filters. The peer credentials of each connection
+    /// are available to the backend through `Conn::peer_cred`.
+    #[cfg(unix)]
+    pub async fn serve_unix(self: Arc<Self>, l: UnixListener) -> Result<()> {
+        self.accept_loop(l, false).await
+    }
+
+    pub async fn handle_conn<T: Transport>(&self, mut c: Conn<B, T>) -> Result<()> {
+        let tracker = c.tracker();
+        let mut force_close = self.shutdown.subscribe_force_close();
+        let _registered = self.registry.register(tracker.clone());
+        let _active = self.metrics.connection_opened();
+        c.stream.get_mut().metrics = Some(self.metrics.clone());
+        c.stream.get_mut().write_timeout = self.write_timeout;
+
+        let res = tokio::select! {
+            res = self.run_conn(&mut c) => res,
+            _ = deadline(self.session_timeout) => {
+                c.timed_out("session").await;
+                Ok(())
+            }
+            _ = tracker.kicked() => {
+                conn_log!(c.log(), Level::Info; "disconnected by administrator");
+                c.stream.get_mut().write_response(421, [4,7,0], &["Connection closed by administrator"]).await;
+                Ok(())
+            }
+            _ = shutdown::signaled(&mut force_close) => {
+                conn_log!(c.log(), Level::Info; "closing for shutdown");
+                c.stream.get_mut().write_response(421, [4,3,2], &["Service shutting down"]).await;
+                Ok(())
+            }
+        };
+        // Also ends a BDAT transfer cut short by the client, and logs the
+        // session out.
+        let _ = c.close().await;
+
+        let counters = &c.stream.get_ref().counters;
+        conn_log!(
+            c.log(),
+            Level::Info,
+            bytes_read = counters.read.load(Ordering::Relaxed),
+            bytes_written = counters.written.load(Ordering::Relaxed);
+            "connection closed"
+        );
+        res
+    }
+
+    async fn run_conn<T: Transport>(&self, c: &mut Conn<B, T>) -> Result<()> {
         c.greet(self.domain.clone()).await;
 
+        let mut shutdown = self.shutdown.subscribe();
+        let mut phase = "greeting";
+
         loop {
+            let read_timeout = if phase == "greeting" && !self.greeting_timeout.is_zero() {
+                self.greeting_timeout
+            } else {
+                self.read_timeout
+            };
+
             let mut line = String::new();
-            match c.read_line(&mut line, self).await {
+            let res = tokio::select! {
+                biased;
+
+                // Sessions waiting for a command are told to go away, while a
+                // BDAT transfer in progress may still complete.
+                _ = shutdown::signaled(&mut shutdown), if !c.is_transferring() => {
+                    conn_log!(c.log(), Level::Info; "closing for shutdown");
+                    c.stream.get_mut().write_response(421, [4,3,2], &["Service shutting down"]).await;
+                    let _ = c.close().await;
+                    return Ok(());
+                }
+                res = c.read_line(&mut line, read_timeout) => res,
+            };
+
+            match res {
                 Ok(0) => {
-                    println!("Connection closed");
-                    c.stream.get_mut().write_response(221, [2,4,0], &["Connection closed, bye"]).await;
+                    conn_log!(c.log(), Level::Debug; "client closed the connection");
                     return Ok(());
                 }
                 Ok(_) => {
+                    phase = "command";
                     match parse_cmd(line) {
                         Ok((cmd, arg)) => {
                             c.handle(cmd, arg, self).await;
                         }
                         Err(err) => {
-                            println!("Error222: {}", err);
-                            c.stream.get_mut().write_response(501, [5,5,2], &["Bad command"]).await;
-                            continue;
+                            conn_log!(c.log(), Level::Debug; "bad command: {}", err);
+                            c.protocol_error(501, [5,5,2], "Bad command".to_string(), self).await;
                         }
                     }
+                    // QUIT, or too many errors.
+                    if c.is_closed() {
+                        return Ok(());
+                    }
+                }
+                Err(err) if err.is::<TooLongLine>() => {
+                    c.protocol_error(500, [5,5,2], "Line too long".to_string(), self).await;
+                    if c.is_closed() {
+                        return Ok(());
+                    }
+                }
+                Err(err) if err.is::<InvalidUtf8>() => {
+                    c.protocol_error(500, [5,5,2], "Invalid UTF-8 in command line".to_string(), self).await;
+                    if c.is_closed() {
+                        return Ok(());
+                    }
+                }
+                Err(err) if err.is::<Elapsed>() => {
+                    c.timed_out(phase).await;
+                    return Ok(());
                 }
                 Err(err) => {
-                    println!("Connection error: {}", err);
+                    conn_log!(c.log(), Level::Warn; "connection error: {}", err);
                     c.stream.get_mut().write_response(221, [2,4,0], &["Connection error, sorry"]).await;
-                    return Err(err.into());
+                    return Err(err);
                 }
             }
         }
     }
 
+    /// Listens on `addr`, a host and port, and serves connections.
     pub async fn listen_and_serve(self) -> Result<()> {
         let l = TcpListener::bind(&self.addr).await?;
         Arc::new(self).serve(l).await
     }
 
-    /*
-    pub async fn listen_and_serve_tls(&mut self) -> Result<()> {
-        let tls = self.server.tls_acceptor.as_ref().unwrap();
-        let l = TcpListener::bind(&self.server.addr).await?;
-        let l = tls.accept(l)?;
-        self.serve(l).await
+    /// Listens on a Unix domain socket at `path` and serves connections, as
+    /// usual for LMTP. `addr` is not used.
+    #[cfg(unix)]
+    pub async fn listen_and_serve_unix<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        let l = UnixListener::bind(path)?;
+        Arc::new(self).serve_unix(l).await
     }
-    */
-}
\ No newline at end of file
+
+    pub async fn listen_and_serve_tls(self) -> Result<()> {
+        if self.tls_acceptor.is_none() {
+            bail!(ERR_TLS_WITHOUT_ACCEPTOR);
+        }
+        let l = TcpListener::bind(&self.addr).await?;
+        Arc::new(self).serve_tls(l).await
+    }
+}
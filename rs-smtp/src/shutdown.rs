@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Triggers a graceful shutdown of a `Server`.
+///
+/// Once triggered, the server stops accepting connections, sends
+/// `421 4.3.2 Service shutting down` to every session waiting for a command
+/// and gives transactions still transferring a message until
+/// `Server::shutdown_timeout` to finish. Connections left after that are
+/// closed, their sessions logged out, and `Server::serve` returns.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<bool>>,
+    force_tx: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> Self {
+        let (tx, _) = watch::channel(false);
+        let (force_tx, _) = watch::channel(false);
+        ShutdownHandle {
+            tx: Arc::new(tx),
+            force_tx: Arc::new(force_tx),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.tx.subscribe()
+    }
+
+    /// Tells the connections still open once `Server::shutdown_timeout`
+    /// elapsed to close.
+    pub(crate) fn force_close(&self) {
+        self.force_tx.send_replace(true);
+    }
+
+    pub(crate) fn subscribe_force_close(&self) -> watch::Receiver<bool> {
+        self.force_tx.subscribe()
+    }
+}
+
+/// Resolves once shutdown, or closing connections, has been triggered.
+pub(crate) async fn signaled(rx: &mut watch::Receiver<bool>) {
+    while !*rx.borrow_and_update() {
+        if rx.changed().await.is_err() {
+            // The handle is gone, so shutdown can never be triggered.
+            std::future::pending::<()>().await;
+        }
+    }
+}
@@ -1,28 +1,67 @@
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
+use log::Level;
 
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
 use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
 use crate::data::{ENHANCED_CODE_NOT_SET, NO_ENHANCED_CODE, EnhancedCode};
+use crate::logging::{conn_log, LogContext};
+use crate::metrics::Metrics;
+use crate::timeout::with_timeout;
 
 const CRNL: [u8; 2] = [b'\r', b'\n'];
 //const DOTCRNL: [u8; 3] = [b'.', b'\r', b'\n'];
 
-pub struct MyStream {
-    pub unsafe_stream: Option<TcpStream>,
-    pub safe_stream: Option<TlsStream<TcpStream>>,
+/// A transport a connection can be served on, such as a `TcpStream`, a
+/// `UnixStream` or a `tokio::io::DuplexStream`.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {}
+
+/// Bytes transferred on a connection, in both directions.
+#[derive(Default)]
+pub struct ByteCounters {
+    pub read: AtomicU64,
+    pub written: AtomicU64,
+}
+
+pub struct MyStream<T: Transport> {
+    pub unsafe_stream: Option<T>,
+    pub safe_stream: Option<TlsStream<T>>,
     pub limit: usize,
+    pub counters: Arc<ByteCounters>,
+    pub log: LogContext,
+    pub metrics: Option<Metrics>,
+    pub write_timeout: Duration,
 }
 
-impl MyStream {
-    pub fn new(unsafe_stream: TcpStream) -> Self {
+impl<T: Transport> MyStream<T> {
+    pub fn new(unsafe_stream: T) -> Self {
         Self {
             unsafe_stream: Some(unsafe_stream),
             safe_stream: None,
             limit: 0,
+            counters: Arc::new(ByteCounters::default()),
+            log: LogContext::default(),
+            metrics: None,
+            write_timeout: Duration::from_secs(0),
+        }
+    }
+
+    pub fn new_tls(safe_stream: TlsStream<T>) -> Self {
+        Self {
+            unsafe_stream: None,
+            safe_stream: Some(safe_stream),
+            limit: 0,
+            counters: Arc::new(ByteCounters::default()),
+            log: LogContext::default(),
+            metrics: None,
+            write_timeout: Duration::from_secs(0),
         }
     }
 
@@ -30,45 +69,78 @@ impl MyStream {
         self.safe_stream.is_some()
     }
 
-    pub async fn starttls(&mut self, acceptor: TlsAcceptor) -> Result<()> {
+    pub fn is_closed(&self) -> bool {
+        self.unsafe_stream.is_none() && self.safe_stream.is_none()
+    }
+
+    pub fn can_starttls(&self) -> bool {
+        self.unsafe_stream.is_some()
+    }
+
+    /// Performs the TLS handshake, failing with a
+    /// `tokio::time::error::Elapsed` error if it takes longer than
+    /// `handshake_timeout`. A zero `handshake_timeout` means no timeout.
+    pub async fn starttls(&mut self, acceptor: TlsAcceptor, handshake_timeout: Duration) -> Result<()> {
         let stream = self.unsafe_stream.take().unwrap();
-        let stream = acceptor.accept(stream).await?;
+        let stream = with_timeout(handshake_timeout, acceptor.accept(stream)).await??;
         self.safe_stream = Some(stream);
         Ok(())
     }
 
     pub async fn print_line(&mut self, line: &str) -> Result<()> {
-        self.write_all(line.as_bytes()).await?;
-        self.write_all(&CRNL).await?;
-        self.flush().await.map_err(|e| anyhow!(e))
+        let write_timeout = self.write_timeout;
+        with_timeout(write_timeout, async {
+            self.write_all(line.as_bytes()).await?;
+            self.write_all(&CRNL).await?;
+            self.flush().await
+        })
+        .await?
+        .map_err(|e| anyhow!(e))
     }
 
     pub async fn write_response(&mut self, code: u16, mut ec: EnhancedCode, texts: &[&str]) {
+        if self.is_closed() {
+            return;
+        }
+
         if ec == ENHANCED_CODE_NOT_SET {
             let cat = code / 100;
             match cat {
-                2 | 4 | 5 => ec = [5, 5, 0],
+                2 | 4 | 5 => ec = [cat as i8, 0, 0],
                 _ => ec = NO_ENHANCED_CODE,
             }
         }
 
-        for text in texts {
-            let _ = self.print_line(&format!("{}-{}", code, text))
-                .await;
+        let level = if code >= 400 { Level::Info } else { Level::Debug };
+        conn_log!(&self.log, level, code = code; "reply: {}", texts[0]);
+        if let Some(metrics) = &self.metrics {
+            metrics.reply(code);
         }
+
+        let (last, rest) = texts.split_last().unwrap();
+        let mut lines: Vec<String> = rest.iter().map(|text| format!("{}-{}", code, text)).collect();
         if ec == NO_ENHANCED_CODE {
-            let _ = self.print_line(&format!("{} {}", code, texts.last().unwrap()))
-                .await;
+            lines.push(format!("{} {}", code, last));
         } else {
-            let _ = self.print_line(&format!(
-                    "{} {}.{}.{} {}",
-                    code,
-                    ec[0],
-                    ec[1],
-                    ec[2],
-                    texts.last().unwrap()
-                ))
-                .await;
+            lines.push(format!(
+                "{} {}.{}.{} {}",
+                code,
+                ec[0],
+                ec[1],
+                ec[2],
+                last
+            ));
+        }
+
+        for line in lines {
+            if let Err(err) = self.print_line(&line).await {
+                // The client is gone or not reading: drop the connection
+                // rather than keep talking to it.
+                conn_log!(&self.log, Level::Warn; "error writing reply: {}", err);
+                self.unsafe_stream = None;
+                self.safe_stream = None;
+                return;
+            }
         }
     }
 
@@ -83,35 +155,44 @@ impl MyStream {
     }
 }
 
-impl AsyncRead for MyStream {
+impl<T: Transport> AsyncRead for MyStream<T> {
     fn poll_read(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        if self.unsafe_stream.is_some() {
-            return AsyncRead::poll_read(Pin::new(self.get_mut().unsafe_stream.as_mut().unwrap()), cx, buf);
-        }
-        if self.safe_stream.is_some() {
-            return AsyncRead::poll_read(Pin::new(self.get_mut().safe_stream.as_mut().unwrap()), cx, buf);
-        }
-        std::task::Poll::Ready(Ok(()))
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = if let Some(stream) = this.unsafe_stream.as_mut() {
+            AsyncRead::poll_read(Pin::new(stream), cx, buf)
+        } else if let Some(stream) = this.safe_stream.as_mut() {
+            AsyncRead::poll_read(Pin::new(stream), cx, buf)
+        } else {
+            return std::task::Poll::Ready(Ok(()));
+        };
+        this.counters.read.fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        res
     }
 }
 
-impl AsyncWrite for MyStream {
+impl<T: Transport> AsyncWrite for MyStream<T> {
     fn poll_write(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<std::io::Result<usize>> {
-        if self.unsafe_stream.is_some() {
-            return AsyncWrite::poll_write(Pin::new(self.get_mut().unsafe_stream.as_mut().unwrap()), cx, buf);
-        }
-        if self.safe_stream.is_some() {
-            return AsyncWrite::poll_write(Pin::new(self.get_mut().safe_stream.as_mut().unwrap()), cx, buf);
+        let this = self.get_mut();
+        let res = if let Some(stream) = this.unsafe_stream.as_mut() {
+            AsyncWrite::poll_write(Pin::new(stream), cx, buf)
+        } else if let Some(stream) = this.safe_stream.as_mut() {
+            AsyncWrite::poll_write(Pin::new(stream), cx, buf)
+        } else {
+            return std::task::Poll::Ready(Ok(0));
+        };
+        if let std::task::Poll::Ready(Ok(n)) = res {
+            this.counters.written.fetch_add(n as u64, Ordering::Relaxed);
         }
-        std::task::Poll::Ready(Ok(0))
+        res
     }
 
     fn poll_flush(
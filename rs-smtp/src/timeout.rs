@@ -0,0 +1,120 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::watch;
+use tokio::time::{error::Elapsed, Sleep};
+
+/// Like `tokio::time::timeout`, but a zero duration means no timeout rather
+/// than an instant one.
+pub async fn with_timeout<F: Future>(duration: Duration, f: F) -> Result<F::Output, Elapsed> {
+    if duration.is_zero() {
+        return Ok(f.await);
+    }
+    tokio::time::timeout(duration, f).await
+}
+
+/// Sleeps for `duration`, or forever if it is zero.
+pub async fn deadline(duration: Duration) {
+    if duration.is_zero() {
+        std::future::pending::<()>().await;
+    }
+    tokio::time::sleep(duration).await;
+}
+
+/// Wraps the message reader handed to `Session::data` to fail reads once the
+/// client sent nothing for `block_timeout`, and to signal when the session
+/// isn't reading.
+pub struct TimedReader<R> {
+    inner: R,
+    block_timeout: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+    pub timed_out: bool,
+    idle: watch::Sender<bool>,
+}
+
+impl<R: AsyncRead + Unpin> TimedReader<R> {
+    pub fn new(inner: R, block_timeout: Duration) -> Self {
+        let (idle, _) = watch::channel(true);
+        TimedReader {
+            inner,
+            block_timeout,
+            sleep: None,
+            timed_out: false,
+            idle,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Returns a receiver which is true while the session isn't waiting on
+    /// a read: before its first read, and after each read which returned,
+    /// until it reads again. The end of the message leaves it true.
+    pub fn idle(&self) -> watch::Receiver<bool> {
+        self.idle.subscribe()
+    }
+
+    fn set_idle(&self, idle: bool) {
+        self.idle.send_if_modified(|cur| std::mem::replace(cur, idle) != idle);
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TimedReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.timed_out {
+            return Poll::Ready(Err(Error::new(ErrorKind::TimedOut, "smtp: data block timeout")));
+        }
+
+        this.set_idle(false);
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                this.sleep = None;
+                this.set_idle(true);
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                if this.block_timeout.is_zero() {
+                    return Poll::Pending;
+                }
+                let block_timeout = this.block_timeout;
+                let sleep = this.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(block_timeout)));
+                if sleep.as_mut().poll(cx).is_ready() {
+                    this.timed_out = true;
+                    return Poll::Ready(Err(Error::new(ErrorKind::TimedOut, "smtp: data block timeout")));
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Resolves once `idle` stayed true for `duration`, or never if `duration`
+/// is zero.
+pub async fn after_idle(mut idle: watch::Receiver<bool>, duration: Duration) {
+    if duration.is_zero() {
+        std::future::pending::<()>().await;
+    }
+    loop {
+        if idle.wait_for(|idle| *idle).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => return,
+            closed = async { idle.wait_for(|idle| !*idle).await.is_err() } => {
+                if closed {
+                    std::future::pending::<()>().await;
+                }
+            }
+        }
+    }
+}